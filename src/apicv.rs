@@ -0,0 +1,183 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! 30.5 Virtualizing MSR-Based APIC Accesses
+//! When the “virtualize x2APIC mode” VM-execution control is 1, the processor virtualizes accesses to some of the
+//! x2APIC MSRs (800H–8FFH) through the virtual-APIC page, provided they are not intercepted by the MSR bitmaps.
+
+use crate::consts::x2apic::{X2APIC_MSE_REG_BASE, X2APIC_MSE_REG_SIZE};
+
+/// IA32_TSC_DEADLINE MSR, programming the APIC timer in TSC-deadline mode.
+pub const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// x2APIC MSRs whose reads are virtualized with “APIC-register virtualization”, relative to 800H.
+///
+/// This is the list of Section 30.5, without the current count register (839H) which is emulated by software.
+const READ_VIRTUALIZED_MSRS: &[core::ops::Range<u32>] = &[
+    0x02..0x04, // ID, Version
+    0x08..0x09, // TPR
+    0x0A..0x0B, // PPR
+    0x0D..0x0E, // LDR
+    0x0F..0x29, // SVR, ISR, TMR, IRR, ESR
+    0x2F..0x31, // LVT CMCI, ICR
+    0x32..0x39, // LVT Timer, Thermal, PMC, LINT0, LINT1, Error, Initial Count
+    0x3E..0x3F, // Divide Configuration
+];
+/// LVT CMCI, which the local APIC may not implement.
+pub(crate) const LVT_CMCI_MSR: u32 = 0x82F;
+/// TPR, whose writes are virtualized with “virtualize x2APIC mode”.
+const TPR_MSR: u32 = 0x08;
+/// EOI and SELF IPI, whose writes are virtualized with “virtual-interrupt delivery”.
+const EOI_MSR: u32 = 0x0B;
+const SELF_IPI_MSR: u32 = 0x3F;
+
+/// The APIC virtualization VM-execution controls used by the VMM for a vCPU.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ApicvFeatures {
+    /// “Virtualize x2APIC mode”, which implies “use TPR shadow”.
+    pub virtualize_x2apic_mode: bool,
+    /// “APIC-register virtualization”.
+    pub register_virtualization: bool,
+    /// “Virtual-interrupt delivery”.
+    pub virtual_interrupt_delivery: bool,
+}
+
+/// The x2APIC MSRs to intercept in the VMX MSR bitmaps.
+///
+/// Bit `n` of `read` or `write` stands for MSR 800H + `n`, so each bitmap can be copied as is to offset 100H of
+/// the corresponding low-MSR bitmap. IA32_TSC_DEADLINE is always intercepted, as the APIC timer is emulated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct X2ApicMsrIntercepts {
+    /// Intercepted reads of MSRs 800H–8FFH.
+    pub read: [u64; 4],
+    /// Intercepted writes of MSRs 800H–8FFH.
+    pub write: [u64; 4],
+}
+
+impl X2ApicMsrIntercepts {
+    /// The intercepts for the local APIC in x2APIC mode (`x2apic` true) or not, with the VM-execution `features`.
+    ///
+    /// All accesses are intercepted unless the local APIC is in x2APIC mode and “virtualize x2APIC mode” is used,
+    /// since they either raise #GP or would reach the physical x2APIC.
+    pub fn new(features: ApicvFeatures, x2apic: bool) -> Self {
+        let mut intercepts = Self {
+            read: [u64::MAX; 4],
+            write: [u64::MAX; 4],
+        };
+
+        if !x2apic || !features.virtualize_x2apic_mode {
+            return intercepts;
+        }
+
+        if features.register_virtualization {
+            for msr in READ_VIRTUALIZED_MSRS.iter().cloned().flatten() {
+                clear_bit(&mut intercepts.read, msr);
+            }
+        }
+
+        clear_bit(&mut intercepts.write, TPR_MSR);
+        if features.virtual_interrupt_delivery {
+            clear_bit(&mut intercepts.write, EOI_MSR);
+            clear_bit(&mut intercepts.write, SELF_IPI_MSR);
+        }
+
+        intercepts
+    }
+
+    /// Intercept reads of `msr`, one of the MSRs 800H–8FFH.
+    pub(crate) fn intercept_read(&mut self, msr: u32) {
+        set_bit(&mut self.read, msr - X2APIC_MSE_REG_BASE as u32);
+    }
+
+    /// Returns whether reads of `msr` are intercepted.
+    pub fn intercepts_read(&self, msr: u32) -> bool {
+        is_intercepted(&self.read, msr)
+    }
+
+    /// Returns whether writes to `msr` are intercepted.
+    pub fn intercepts_write(&self, msr: u32) -> bool {
+        is_intercepted(&self.write, msr)
+    }
+}
+
+fn clear_bit(bitmap: &mut [u64; 4], msr: u32) {
+    bitmap[msr as usize >> 6] &= !(1 << (msr & 0x3f));
+}
+
+fn set_bit(bitmap: &mut [u64; 4], msr: u32) {
+    bitmap[msr as usize >> 6] |= 1 << (msr & 0x3f);
+}
+
+fn is_intercepted(bitmap: &[u64; 4], msr: u32) -> bool {
+    let base = X2APIC_MSE_REG_BASE as u32;
+    if (base..base + X2APIC_MSE_REG_SIZE as u32).contains(&msr) {
+        let msr = msr - base;
+        bitmap[msr as usize >> 6] & (1 << (msr & 0x3f)) != 0
+    } else {
+        // IA32_TSC_DEADLINE and any other MSR are not covered by the x2APIC range.
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ApicvFeatures, IA32_TSC_DEADLINE, X2ApicMsrIntercepts};
+
+    #[test]
+    fn test_x2apic_msr_intercepts() {
+        let all = ApicvFeatures {
+            virtualize_x2apic_mode: true,
+            register_virtualization: true,
+            virtual_interrupt_delivery: true,
+        };
+
+        // xAPIC mode or no x2APIC virtualization: everything is intercepted
+        for intercepts in [
+            X2ApicMsrIntercepts::new(all, false),
+            X2ApicMsrIntercepts::new(ApicvFeatures::default(), true),
+        ] {
+            assert_eq!(intercepts.read, [u64::MAX; 4]);
+            assert_eq!(intercepts.write, [u64::MAX; 4]);
+        }
+
+        let intercepts = X2ApicMsrIntercepts::new(all, true);
+        // ID, TPR, IRR and ICR reads are passed through, current count and SELF IPI are not
+        assert!(!intercepts.intercepts_read(0x802));
+        assert!(!intercepts.intercepts_read(0x808));
+        assert!(!intercepts.intercepts_read(0x827));
+        assert!(!intercepts.intercepts_read(0x830));
+        assert!(intercepts.intercepts_read(0x839));
+        assert!(intercepts.intercepts_read(0x83F));
+        // TPR, EOI and SELF IPI writes are virtualized, ICR and Initial Count are not
+        assert!(!intercepts.intercepts_write(0x808));
+        assert!(!intercepts.intercepts_write(0x80B));
+        assert!(!intercepts.intercepts_write(0x83F));
+        assert!(intercepts.intercepts_write(0x830));
+        assert!(intercepts.intercepts_write(0x838));
+        // TSC deadline is always intercepted
+        assert!(intercepts.intercepts_read(IA32_TSC_DEADLINE));
+        assert!(intercepts.intercepts_write(IA32_TSC_DEADLINE));
+
+        // Without virtual-interrupt delivery, only TPR writes are virtualized
+        let intercepts = X2ApicMsrIntercepts::new(
+            ApicvFeatures {
+                virtual_interrupt_delivery: false,
+                ..all
+            },
+            true,
+        );
+        assert!(!intercepts.intercepts_write(0x808));
+        assert!(intercepts.intercepts_write(0x80B));
+        assert!(intercepts.intercepts_write(0x83F));
+    }
+}
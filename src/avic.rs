@@ -0,0 +1,285 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! AMD64 APM Vol. 2, 15.29 Advanced Virtual Interrupt Controller
+//! AVIC virtualizes the xAPIC registers of a guest through a per-vCPU APIC backing page, which has the layout of
+//! the virtual-APIC page, and delivers IPIs between running vCPUs without a #VMEXIT through the physical and
+//! logical APIC ID tables of the VM.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use axaddrspace::{HostPhysAddr, HostVirtAddr};
+use axvisor_api::memory;
+use memory_addr::PAGE_SIZE_4K;
+
+use crate::regs::DESTINATION_FORMAT::Model::Value as APICDestinationFormat;
+
+/// The AVIC doorbell MSR, whose writes with a physical APIC ID make the CPU running a guest with AVIC process its
+/// IRR without a #VMEXIT.
+pub const AVIC_DOORBELL_MSR: u32 = 0xC001_011B;
+
+/// Physical APIC ID table entry:
+/// Host Physical APIC ID (bits 7:0) of the CPU running the vCPU.
+const PHYS_HOST_APIC_ID_MASK: u64 = 0xff;
+/// APIC Backing Page Pointer (bits 51:12).
+const PHYS_BACKING_PAGE_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// IsRunning (bit 62): the vCPU is running on the CPU with the host physical APIC ID.
+const PHYS_IS_RUNNING: u64 = 1 << 62;
+/// Valid (bit 63).
+const PHYS_VALID: u64 = 1 << 63;
+
+/// Logical APIC ID table entry:
+/// Guest Physical APIC ID (bits 7:0).
+const LOGICAL_GUEST_APIC_ID_MASK: u32 = 0xff;
+/// Valid (bit 31).
+const LOGICAL_VALID: u32 = 1 << 31;
+
+/// The largest guest physical APIC ID with an entry in the physical APIC ID table, as FFH is the broadcast ID.
+pub(crate) const AVIC_MAX_PHYSICAL_ID: u32 = 0xfe;
+
+/// EXITINFO2 of an AVIC_INCOMPLETE_IPI #VMEXIT: the reason for the failure (bits 63:32).
+const INCOMPLETE_IPI_ID_SHIFT: u64 = 32;
+/// EXITINFO1 of an AVIC_NOACCEL #VMEXIT: the offset of the register (bits 11:4), and whether it was a write
+/// (bit 32).
+const NOACCEL_OFFSET_MASK: u64 = 0xff0;
+const NOACCEL_WRITE: u64 = 1 << 32;
+
+/// Writes the physical APIC ID `host_apic_id` of a CPU running a vCPU to the [`AVIC_DOORBELL_MSR`] of the current
+/// CPU, on behalf of the APIC bus.
+pub type AvicDoorbell = fn(host_apic_id: u32);
+
+/// The causes of an AVIC_INCOMPLETE_IPI #VMEXIT, which is a trap: the ICR already holds the value written by the
+/// guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IncompleteIpiCause {
+    /// The IPI is not a fixed, edge-triggered interrupt, e.g. an NMI, INIT or SIPI, and was not delivered.
+    InvalidIntType,
+    /// The IPI was recorded in the IRR of at least one target that is not running.
+    TargetNotRunning,
+    /// A target has no valid entry in the physical or logical APIC ID table, and the IPI was not delivered.
+    InvalidTarget,
+    /// A target has an invalid backing page pointer, and the IPI was not delivered.
+    InvalidBackingPage,
+}
+
+impl IncompleteIpiCause {
+    /// Decode the cause from EXITINFO2.
+    pub(crate) const fn from_exit_info2(exit_info2: u64) -> Option<Self> {
+        match exit_info2 >> INCOMPLETE_IPI_ID_SHIFT {
+            0 => Some(Self::InvalidIntType),
+            1 => Some(Self::TargetNotRunning),
+            2 => Some(Self::InvalidTarget),
+            3 => Some(Self::InvalidBackingPage),
+            _ => None,
+        }
+    }
+}
+
+/// Decode EXITINFO1 of an AVIC_NOACCEL #VMEXIT into the register offset and whether the access was a write.
+pub(crate) const fn decode_noaccel(exit_info1: u64) -> (usize, bool) {
+    (
+        (exit_info1 & NOACCEL_OFFSET_MASK) as usize,
+        exit_info1 & NOACCEL_WRITE != 0,
+    )
+}
+
+/// Whether a write to the register at `offset` is a trap: the write has been done to the backing page and the
+/// instruction completed, and only its side effects are left to the VMM. Other unaccelerated accesses are faults,
+/// to be emulated from the instruction.
+pub(crate) const fn is_noaccel_trap(offset: usize) -> bool {
+    matches!(
+        offset,
+        0x20    // ID
+        | 0xB0  // EOI
+        | 0xD0  // LDR
+        | 0xE0  // DFR
+        | 0xF0  // SVR
+        | 0x280 // ESR
+        | 0x300 // ICR_LO
+        | 0x320
+            ..=0x370 // LVT Timer, Thermal, PMC, LINT0, LINT1, Error
+        | 0x380 // Initial Count
+        | 0x3E0 // Divide Configuration
+    )
+}
+
+/// The index of the entry for the logical APIC ID in `ldr` in the given destination format: the bit number of the
+/// logical ID in flat mode, or the cluster number times 4 plus the bit number in cluster mode. The LDR must hold a
+/// single bit, outside of the broadcast cluster FH.
+pub(crate) const fn logical_id_index(ldr: u32, model: APICDestinationFormat) -> Option<usize> {
+    let logical_id = ldr >> 24;
+    match model {
+        APICDestinationFormat::Flat if logical_id.is_power_of_two() => {
+            Some(logical_id.trailing_zeros() as usize)
+        }
+        APICDestinationFormat::Cluster
+            if (logical_id & 0xf).is_power_of_two() && logical_id >> 4 != 0xf =>
+        {
+            Some(((logical_id >> 4) << 2) as usize | (logical_id & 0xf).trailing_zeros() as usize)
+        }
+        _ => None,
+    }
+}
+
+#[repr(C, align(4096))]
+struct PhysicalIdTable([AtomicU64; PAGE_SIZE_4K / 8]);
+
+#[repr(C, align(4096))]
+struct LogicalIdTable([AtomicU32; PAGE_SIZE_4K / 4]);
+
+/// The physical and logical APIC ID tables of a VM, shared by the processor and the VMM.
+pub(crate) struct AvicTables {
+    physical: Box<PhysicalIdTable>,
+    logical: Box<LogicalIdTable>,
+    doorbell: AvicDoorbell,
+}
+
+impl AvicTables {
+    pub(crate) fn new(doorbell: AvicDoorbell) -> Self {
+        Self {
+            physical: Box::new(PhysicalIdTable(core::array::from_fn(|_| AtomicU64::new(0)))),
+            logical: Box::new(LogicalIdTable(core::array::from_fn(|_| AtomicU32::new(0)))),
+            doorbell,
+        }
+    }
+
+    /// The physical address of the physical APIC ID table, for the AVIC_PHYSICAL_TABLE_PTR field of the VMCB.
+    pub(crate) fn physical_table_addr(&self) -> HostPhysAddr {
+        memory::virt_to_phys(HostVirtAddr::from_usize(self.physical.0.as_ptr() as usize))
+    }
+
+    /// The physical address of the logical APIC ID table, for the AVIC_LOGICAL_TABLE_PTR field of the VMCB.
+    pub(crate) fn logical_table_addr(&self) -> HostPhysAddr {
+        memory::virt_to_phys(HostVirtAddr::from_usize(self.logical.0.as_ptr() as usize))
+    }
+
+    /// Ring the doorbell of the CPU with physical APIC ID `host_apic_id`.
+    pub(crate) fn ring_doorbell(&self, host_apic_id: u32) {
+        (self.doorbell)(host_apic_id);
+    }
+
+    /// Make the entry of `apic_id` valid with the backing page at `backing_page`, or invalidate it with `None`.
+    /// The vCPU starts out not running.
+    pub(crate) fn set_backing_page(&self, apic_id: u32, backing_page: Option<HostPhysAddr>) {
+        let entry = backing_page.map_or(0, |paddr| {
+            (paddr.as_usize() as u64 & PHYS_BACKING_PAGE_MASK) | PHYS_VALID
+        });
+        self.physical.0[apic_id as usize].store(entry, Ordering::Release);
+    }
+
+    /// Set IsRunning with the host physical APIC ID of the CPU running `apic_id`, or clear it with `None`.
+    pub(crate) fn set_running(&self, apic_id: u32, host_apic_id: Option<u32>) {
+        let _ = self.physical.0[apic_id as usize].fetch_update(
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            |entry| {
+                let entry = entry & !(PHYS_HOST_APIC_ID_MASK | PHYS_IS_RUNNING);
+                Some(match host_apic_id {
+                    Some(host) => entry | (host as u64 & PHYS_HOST_APIC_ID_MASK) | PHYS_IS_RUNNING,
+                    None => entry,
+                })
+            },
+        );
+    }
+
+    /// The host physical APIC ID of the CPU running `apic_id`, if its entry is valid and IsRunning is set.
+    pub(crate) fn running_on(&self, apic_id: u32) -> Option<u32> {
+        let entry = self
+            .physical
+            .0
+            .get(apic_id as usize)?
+            .load(Ordering::SeqCst);
+        (entry & (PHYS_VALID | PHYS_IS_RUNNING) == PHYS_VALID | PHYS_IS_RUNNING)
+            .then_some((entry & PHYS_HOST_APIC_ID_MASK) as u32)
+    }
+
+    /// Point the logical APIC ID table entry `index` to `apic_id`.
+    pub(crate) fn set_logical(&self, index: usize, apic_id: u32) {
+        self.logical.0[index].store(
+            (apic_id & LOGICAL_GUEST_APIC_ID_MASK) | LOGICAL_VALID,
+            Ordering::Release,
+        );
+    }
+
+    /// Invalidate the logical APIC ID table entry `index` if it still points to `apic_id`.
+    pub(crate) fn clear_logical(&self, index: usize, apic_id: u32) {
+        let _ = self.logical.0[index].compare_exchange(
+            (apic_id & LOGICAL_GUEST_APIC_ID_MASK) | LOGICAL_VALID,
+            0,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noop_doorbell(_host_apic_id: u32) {}
+
+    #[test]
+    fn test_logical_id_index() {
+        use APICDestinationFormat::{Cluster, Flat};
+
+        // Flat model: the bit number of the 8-bit logical ID
+        assert_eq!(logical_id_index(0x0100_0000, Flat), Some(0));
+        assert_eq!(logical_id_index(0x8000_0000, Flat), Some(7));
+        assert_eq!(logical_id_index(0x0300_0000, Flat), None);
+        assert_eq!(logical_id_index(0, Flat), None);
+
+        // Cluster model: cluster 2, bit 3
+        assert_eq!(logical_id_index(0x2800_0000, Cluster), Some(11));
+        assert_eq!(logical_id_index(0xE100_0000, Cluster), Some(56));
+        // Broadcast cluster, or several bits
+        assert_eq!(logical_id_index(0xF100_0000, Cluster), None);
+        assert_eq!(logical_id_index(0x2300_0000, Cluster), None);
+    }
+
+    #[test]
+    fn test_physical_id_table_entry() {
+        let tables = AvicTables::new(noop_doorbell);
+        tables.set_running(1, Some(3));
+        assert_eq!(tables.running_on(1), None);
+
+        tables.set_backing_page(1, Some(HostPhysAddr::from_usize(0x1234_5000)));
+        assert_eq!(tables.running_on(1), None);
+        assert_eq!(
+            tables.physical.0[1].load(Ordering::Relaxed),
+            0x1234_5000 | PHYS_VALID
+        );
+
+        tables.set_running(1, Some(3));
+        assert_eq!(tables.running_on(1), Some(3));
+        tables.set_running(1, Some(5));
+        assert_eq!(tables.running_on(1), Some(5));
+        tables.set_running(1, None);
+        assert_eq!(tables.running_on(1), None);
+        assert_eq!(
+            tables.physical.0[1].load(Ordering::Relaxed),
+            0x1234_5000 | PHYS_VALID
+        );
+
+        // Entries pointing to another vCPU are kept
+        tables.set_logical(2, 1);
+        tables.clear_logical(2, 0);
+        assert_eq!(
+            tables.logical.0[2].load(Ordering::Relaxed),
+            1 | LOGICAL_VALID
+        );
+        tables.clear_logical(2, 1);
+        assert_eq!(tables.logical.0[2].load(Ordering::Relaxed), 0);
+    }
+}
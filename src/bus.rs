@@ -0,0 +1,816 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering};
+
+use axaddrspace::{GuestPhysAddr, HostPhysAddr, HostVirtAddr};
+use axerrno::{AxError, AxResult};
+use axvisor_api::{
+    memory::PhysFrame,
+    vmm::{self, VMId},
+};
+use memory_addr::PAGE_SIZE_4K;
+use spin::RwLock;
+
+use crate::avic::{AVIC_MAX_PHYSICAL_ID, AvicDoorbell, AvicTables, logical_id_index};
+use crate::consts::{NMI_VECTOR, xapic::XAPIC_BROADCAST_DEST_ID};
+use crate::posted::PostedInterruptDesc;
+use crate::regs::{
+    DESTINATION_FORMAT::{self, Model::Value as APICDestinationFormat},
+    MSI_ADDRESS, MSI_DATA, MsiAddressRegisterLocal, MsiDataRegisterLocal,
+};
+use crate::utils::VCpuSet;
+
+/// The virtual-wire mode of a VM, i.e. where the interrupt output (INTR) of the virtual 8259 PIC goes.
+///
+/// See MultiProcessor Specification 1.4, Section 3.6.2, and the ExtINT delivery mode in SDM Vol. 3A, Section 11.5.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum WireMode {
+    /// The PIC output is wired to the INTR pin of the processor, bypassing the local APICs.
+    ///
+    /// This is the mode after reset, and the VMM takes the vector from the PIC directly.
+    Intr = 0,
+    /// The PIC output goes through the LINT0 pin of a local APIC programmed with the ExtINT delivery mode.
+    ///
+    /// The VMM drives LINT0 with the PIC output, and takes the vector from the PIC when the local APIC reports a
+    /// pending ExtINT interrupt.
+    Lapic = 1,
+    /// The PIC output is not delivered anywhere, as LINT0 has been masked.
+    Null = 2,
+}
+
+impl WireMode {
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            0 => WireMode::Intr,
+            1 => WireMode::Lapic,
+            2 => WireMode::Null,
+            _ => panic!("Invalid wire mode"),
+        }
+    }
+}
+
+/// Sends the notification vector `nv` of a posted interrupt to the physical CPU with APIC ID `ndst`, in the
+/// format of the NDST field of the posted-interrupt descriptor.
+pub type PostedInterruptNotifier = fn(ndst: u32, nv: u8);
+
+/// Makes vCPU `vcpu_id` of VM `vm_id` notice the interrupts recorded for its local APIC by other CPUs: wakes it up
+/// if it's halted, or forces a VM exit if it's running on another CPU.
+pub type VCpuKicker = fn(vm_id: VMId, vcpu_id: usize);
+
+/// Sends the EOI message for `vector` to the I/O APICs of VM `vm_id`, which clear the Remote IRR of the
+/// level-triggered redirection table entries with that vector.
+pub type IoApicEoiHandler = fn(vm_id: VMId, vector: u8);
+
+/// The virtual-APIC page of a local APIC, null once the local APIC is dropped.
+struct ApicPagePtr(*mut u32);
+
+impl Default for ApicPagePtr {
+    fn default() -> Self {
+        Self(core::ptr::null_mut())
+    }
+}
+
+// SAFETY: the page is only accessed under the lock of `LapicDest::apic_page`, which its local APIC takes for writing
+// to withdraw the page before freeing it.
+unsafe impl Send for ApicPagePtr {}
+unsafe impl Sync for ApicPagePtr {}
+
+/// Translates the guest physical address `gpa` of VM `vm_id` to the host virtual address it is mapped at, or
+/// returns `None` if it's not backed by guest memory.
+pub type GuestPhysTranslator = fn(vm_id: VMId, gpa: GuestPhysAddr) -> Option<HostVirtAddr>;
+
+/// The registers of a local APIC that select which interrupt messages it accepts, published to the bus by the
+/// local APIC whenever they change, and its posted-interrupt descriptor.
+#[derive(Default)]
+struct LapicDest {
+    ldr: AtomicU32,
+    dfr: AtomicU32,
+    x2apic: AtomicBool,
+    /// Whether fixed interrupts from other vCPUs are posted to `pi_desc` rather than injected.
+    posted: AtomicBool,
+    pi_desc: PostedInterruptDesc,
+    /// Fixed interrupts accepted from any CPU, waiting to be moved to the IRR by the vCPU itself.
+    remote_irr: [AtomicU64; 4],
+    /// The level-triggered ones among `remote_irr`.
+    remote_tmr: [AtomicU64; 4],
+    /// Whether an INIT was sent to the local APIC, which resets it the next time the vCPU moves `remote_irr` to its
+    /// IRR.
+    init_pending: AtomicBool,
+    /// The virtual-APIC page of the local APIC, for remote reads from other local APICs, and for setting IRR bits
+    /// when it's the AVIC backing page. Other CPUs hold the read lock while they access the page, so that it's not
+    /// freed under them.
+    apic_page: RwLock<ApicPagePtr>,
+    /// Whether the local APIC uses AVIC, i.e. has a valid entry in the physical APIC ID table.
+    avic: AtomicBool,
+    /// The index of the entry of the local APIC in the logical APIC ID table plus 1, or 0 if it has none.
+    avic_logical: AtomicU32,
+}
+
+/// The virtual APIC bus of a VM, holding the VM-level states shared by all local APICs of the VM, and routing
+/// interrupt messages between them.
+///
+/// Create one for each VM, and pass it to [`EmulatedLocalApic::new`](crate::EmulatedLocalApic::new) for each vCPU.
+pub struct ApicBus {
+    vm_id: VMId,
+    wire_mode: AtomicU8,
+    /// Destination registers of the local APIC of each vCPU, indexed by vCPU ID.
+    dests: Vec<LapicDest>,
+    pi_notifier: Option<PostedInterruptNotifier>,
+    vcpu_kicker: Option<VCpuKicker>,
+    ioapic_eoi: Option<IoApicEoiHandler>,
+    /// The physical and logical APIC ID tables, on AMD hosts with AVIC.
+    avic: Option<AvicTables>,
+    gpa_translator: Option<GuestPhysTranslator>,
+    /// The APIC-access page of the VM, released when the bus is dropped.
+    apic_access_page: PhysFrame,
+}
+
+impl ApicBus {
+    /// Create the APIC bus of VM `vm_id` with `vcpu_num` vCPUs, allocating the APIC-access page of the VM.
+    pub fn new(vm_id: VMId, vcpu_num: usize) -> AxResult<Self> {
+        Ok(Self {
+            vm_id,
+            wire_mode: AtomicU8::new(WireMode::Intr as u8),
+            dests: (0..vcpu_num).map(|_| LapicDest::default()).collect(),
+            pi_notifier: None,
+            vcpu_kicker: None,
+            ioapic_eoi: None,
+            avic: None,
+            gpa_translator: None,
+            apic_access_page: PhysFrame::alloc_zero()?,
+        })
+    }
+
+    /// The physical address of the APIC-access page of the VM, see
+    /// [`EmulatedLocalApic::virtual_apic_access_addr`](crate::EmulatedLocalApic::virtual_apic_access_addr).
+    ///
+    /// The page is freed with the bus, so it must be unmapped from the guest physical address space of the VM
+    /// before the last reference to the bus is dropped.
+    pub fn apic_access_addr(&self) -> HostPhysAddr {
+        self.apic_access_page.start_paddr()
+    }
+
+    /// Set the function sending posted-interrupt notifications, which is required for local APICs to enable
+    /// posted interrupts.
+    pub fn set_posted_interrupt_notifier(&mut self, notifier: PostedInterruptNotifier) {
+        self.pi_notifier = Some(notifier);
+    }
+
+    /// Set the function kicking vCPUs, which is required for interrupts from other vCPUs and devices to be noticed
+    /// before the next VM exit of their target.
+    pub fn set_vcpu_kicker(&mut self, kicker: VCpuKicker) {
+        self.vcpu_kicker = Some(kicker);
+    }
+
+    /// Kick `vcpu_id` so that it moves the interrupts recorded by [`Self::post_remote`] to its IRR.
+    fn kick(&self, vcpu_id: usize) {
+        match self.vcpu_kicker {
+            Some(kick) => kick(self.vm_id, vcpu_id),
+            None => debug!(
+                "[VLAPIC] no vCPU kicker, vcpu {vcpu_id} notices interrupts on its next VM exit"
+            ),
+        }
+    }
+
+    /// Set the function broadcasting EOIs of level-triggered interrupts to the I/O APICs of the VM.
+    pub fn set_ioapic_eoi_handler(&mut self, handler: IoApicEoiHandler) {
+        self.ioapic_eoi = Some(handler);
+    }
+
+    /// 11.8.5 Signaling Interrupt Servicing Completion
+    /// Broadcast the EOI of the level-triggered interrupt with `vector` to the I/O APICs.
+    pub(crate) fn broadcast_eoi(&self, vector: u8) {
+        match self.ioapic_eoi {
+            Some(eoi) => eoi(self.vm_id, vector),
+            None => debug!(
+                "[VLAPIC] no I/O APIC EOI handler, EOI broadcast of vector {vector:#x} dropped"
+            ),
+        }
+    }
+
+    /// Allocate the physical and logical APIC ID tables of the VM, which is required for local APICs to enable
+    /// AVIC. `doorbell` is called to notify the CPU running a vCPU of the interrupts set in its IRR.
+    pub fn enable_avic(&mut self, doorbell: AvicDoorbell) {
+        self.avic = Some(AvicTables::new(doorbell));
+    }
+
+    /// The physical address of the AVIC physical APIC ID table, if [`Self::enable_avic`] was called.
+    ///
+    /// It goes to the AVIC_PHYSICAL_TABLE_PTR field of the VMCB of each vCPU, with [`Self::vcpu_num`] minus 1 as the
+    /// highest index of the table.
+    pub fn avic_physical_table_addr(&self) -> Option<HostPhysAddr> {
+        self.avic.as_ref().map(AvicTables::physical_table_addr)
+    }
+
+    /// The physical address of the AVIC logical APIC ID table, for the AVIC_LOGICAL_TABLE_PTR field of the VMCB of
+    /// each vCPU, if [`Self::enable_avic`] was called.
+    pub fn avic_logical_table_addr(&self) -> Option<HostPhysAddr> {
+        self.avic.as_ref().map(AvicTables::logical_table_addr)
+    }
+
+    /// Set the function translating guest physical addresses, which is required for local APICs to access guest
+    /// memory, e.g. to enable paravirtual EOI.
+    pub fn set_guest_phys_translator(&mut self, translator: GuestPhysTranslator) {
+        self.gpa_translator = Some(translator);
+    }
+
+    /// Translate the guest physical address `gpa` of the VM to a host virtual address.
+    pub(crate) fn translate_gpa(&self, gpa: GuestPhysAddr) -> AxResult<HostVirtAddr> {
+        let Some(translate) = self.gpa_translator else {
+            warn!("[VLAPIC] guest memory access needs a translator on the APIC bus");
+            return Err(AxError::BadState);
+        };
+        translate(self.vm_id, gpa).ok_or_else(|| {
+            warn!("[VLAPIC] guest physical address {gpa:?} not backed by memory");
+            AxError::InvalidInput
+        })
+    }
+
+    /// The ID of the VM this bus belongs to.
+    pub const fn vm_id(&self) -> VMId {
+        self.vm_id
+    }
+
+    /// The number of vCPUs, i.e. local APICs, on this bus.
+    pub fn vcpu_num(&self) -> usize {
+        self.dests.len()
+    }
+
+    /// The current virtual-wire mode of the VM, telling the VMM where to deliver the output of the virtual PIC.
+    pub fn wire_mode(&self) -> WireMode {
+        WireMode::from_u8(self.wire_mode.load(Ordering::Acquire))
+    }
+
+    /// Switch the virtual-wire mode to `to` if it's currently one of `from`. Returns whether the mode was switched.
+    pub(crate) fn switch_wire_mode(&self, from: &[WireMode], to: WireMode) -> bool {
+        self.wire_mode
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |mode| {
+                from.contains(&WireMode::from_u8(mode)).then_some(to as u8)
+            })
+            .is_ok()
+    }
+
+    /// Publish the destination registers of the local APIC of `vcpu_id`.
+    pub(crate) fn update_dest(&self, vcpu_id: u32, ldr: u32, dfr: u32, x2apic: bool) {
+        let Some(entry) = self.dests.get(vcpu_id as usize) else {
+            warn!(
+                "[VLAPIC] vcpu {vcpu_id} out of range of APIC bus of VM {}",
+                self.vm_id
+            );
+            return;
+        };
+        entry.ldr.store(ldr, Ordering::Release);
+        entry.dfr.store(dfr, Ordering::Release);
+        entry.x2apic.store(x2apic, Ordering::Release);
+        self.update_avic_logical(vcpu_id);
+    }
+
+    /// Point the logical APIC ID table entry selected by the LDR and DFR of `vcpu_id` to it, and invalidate the one
+    /// it had before. AVIC only virtualizes the xAPIC mode, so there is no entry in x2APIC mode.
+    fn update_avic_logical(&self, vcpu_id: u32) {
+        let (Some(avic), Some(entry)) = (&self.avic, self.dests.get(vcpu_id as usize)) else {
+            return;
+        };
+        let index = if entry.avic.load(Ordering::Acquire) && !entry.x2apic.load(Ordering::Acquire) {
+            DESTINATION_FORMAT::Model
+                .read_as_enum::<APICDestinationFormat>(entry.dfr.load(Ordering::Acquire))
+                .and_then(|model| logical_id_index(entry.ldr.load(Ordering::Acquire), model))
+        } else {
+            None
+        };
+
+        let old = entry
+            .avic_logical
+            .swap(index.map_or(0, |index| index as u32 + 1), Ordering::AcqRel);
+        if old != 0 && Some(old as usize - 1) != index {
+            avic.clear_logical(old as usize - 1, vcpu_id);
+        }
+        if let Some(index) = index {
+            avic.set_logical(index, vcpu_id);
+        }
+    }
+
+    /// Let the processor deliver IPIs to `vcpu_id` through AVIC, with its backing page at `backing_page`.
+    pub(crate) fn add_avic_dest(&self, vcpu_id: u32, backing_page: HostPhysAddr) -> AxResult {
+        let Some(avic) = &self.avic else {
+            warn!("[VLAPIC] AVIC needs the APIC ID tables on the APIC bus");
+            return Err(AxError::BadState);
+        };
+        if vcpu_id > AVIC_MAX_PHYSICAL_ID {
+            warn!("[VLAPIC] APIC ID {vcpu_id} out of range of the AVIC physical APIC ID table");
+            return Err(AxError::InvalidInput);
+        }
+        avic.set_backing_page(vcpu_id, Some(backing_page));
+        self.dests[vcpu_id as usize]
+            .avic
+            .store(true, Ordering::Release);
+        self.update_avic_logical(vcpu_id);
+        Ok(())
+    }
+
+    /// Stop delivering interrupts to `vcpu_id` through AVIC, and remove it from the APIC ID tables.
+    pub(crate) fn remove_avic_dest(&self, vcpu_id: u32) {
+        let Some(avic) = &self.avic else {
+            return;
+        };
+        if let Some(entry) = self.dests.get(vcpu_id as usize)
+            && entry.avic.swap(false, Ordering::AcqRel)
+        {
+            avic.set_backing_page(vcpu_id, None);
+            self.update_avic_logical(vcpu_id);
+        }
+    }
+
+    /// Set the IsRunning bit of `vcpu_id` in the physical APIC ID table with the physical APIC ID of the CPU it's
+    /// loaded on, or clear it with `None` when it's put.
+    pub(crate) fn set_avic_running(&self, vcpu_id: u32, host_apic_id: Option<u32>) {
+        if let Some(avic) = &self.avic
+            && self.dests[vcpu_id as usize].avic.load(Ordering::Acquire)
+        {
+            avic.set_running(vcpu_id, host_apic_id);
+        }
+    }
+
+    /// Call `f` with the IRR register holding `vector` on the backing page of `vcpu_id`, if it uses AVIC.
+    fn with_avic_irr<R>(
+        &self,
+        vcpu_id: usize,
+        vector: u8,
+        f: impl FnOnce(&AtomicU32) -> R,
+    ) -> Option<R> {
+        let entry = self.dests.get(vcpu_id)?;
+        let page = entry.apic_page.read();
+        if !entry.avic.load(Ordering::Acquire) || page.0.is_null() {
+            return None;
+        }
+        // IRR is at 200H, with 32 vectors in each 16-byte slot.
+        let index = 0x20 + (vector as usize >> 5);
+        // SAFETY: the page stays allocated while the read lock is held, see `set_apic_page`. The processor updates
+        // the IRR of the backing page with locked operations, so the bits are set and cleared atomically as well.
+        Some(f(unsafe { AtomicU32::from_ptr(page.0.add(index * 4)) }))
+    }
+
+    /// Make `vcpu_id` notice `vector`, which is set in the IRR of its backing page by the sender or the processor.
+    ///
+    /// The CPU running the vCPU is notified with the doorbell. If it's not running, the vector is taken back from the
+    /// IRR, unless the vCPU already did so meanwhile, recorded with [`Self::post_remote`], and the vCPU is kicked
+    /// awake.
+    pub(crate) fn kick_avic(&self, vcpu_id: usize, vector: u8) {
+        let Some(avic) = &self.avic else {
+            return;
+        };
+        let mask = 1 << (vector & 0x1f);
+        match avic.running_on(vcpu_id as u32) {
+            Some(host_apic_id) => avic.ring_doorbell(host_apic_id),
+            None => {
+                let taken = self.with_avic_irr(vcpu_id, vector, |irr| {
+                    irr.fetch_and(!mask, Ordering::SeqCst) & mask != 0
+                });
+                if taken == Some(true) {
+                    self.post_remote(vcpu_id as _, vector, false);
+                    self.kick(vcpu_id);
+                }
+            }
+        }
+    }
+
+    /// Publish the virtual-APIC page of the local APIC of `vcpu_id`, or withdraw it with a null pointer when the
+    /// local APIC is dropped.
+    ///
+    /// Withdrawing the page waits for the other CPUs accessing it, so it can be freed once this returns.
+    pub(crate) fn set_apic_page(&self, vcpu_id: u32, page: *mut u32) {
+        if let Some(entry) = self.dests.get(vcpu_id as usize) {
+            entry.apic_page.write().0 = page;
+        }
+    }
+
+    /// Read the low 32 bits of the register slot `index` (the register offset divided by 16) of the virtual-APIC
+    /// page of `vcpu_id`, without locking its local APIC. Returns `None` if the local APIC does not exist.
+    pub(crate) fn read_apic_page(&self, vcpu_id: u32, index: usize) -> Option<u32> {
+        let page = self.dests.get(vcpu_id as usize)?.apic_page.read();
+        if page.0.is_null() || index >= PAGE_SIZE_4K / 16 {
+            return None;
+        }
+        // SAFETY: the page stays allocated while the read lock is held, see `set_apic_page`. Registers are 32-bit
+        // aligned values, which the owner may update concurrently as the processor does with APIC virtualization.
+        Some(unsafe { page.0.add(index * 4).read_volatile() })
+    }
+
+    /// The posted-interrupt descriptor of the local APIC of `vcpu_id`.
+    pub(crate) fn pi_desc(&self, vcpu_id: u32) -> &PostedInterruptDesc {
+        &self.dests[vcpu_id as usize].pi_desc
+    }
+
+    /// Record a fixed interrupt with `vector` for the local APIC of `vcpu_id`, without locking it.
+    pub(crate) fn post_remote(&self, vcpu_id: u32, vector: u8, level: bool) {
+        let entry = &self.dests[vcpu_id as usize];
+        let (idx, mask) = (vector as usize >> 6, 1u64 << (vector & 0x3f));
+
+        // The TMR bit is published before the IRR bit, which the vCPU takes first.
+        if level {
+            entry.remote_tmr[idx].fetch_or(mask, Ordering::Release);
+        } else {
+            entry.remote_tmr[idx].fetch_and(!mask, Ordering::Release);
+        }
+        entry.remote_irr[idx].fetch_or(mask, Ordering::Release);
+    }
+
+    /// Take the interrupts recorded by [`Self::post_remote`] for `vcpu_id`, with their trigger modes.
+    pub(crate) fn take_remote(&self, vcpu_id: u32) -> ([u64; 4], [u64; 4]) {
+        let entry = &self.dests[vcpu_id as usize];
+        let irr: [u64; 4] =
+            core::array::from_fn(|i| entry.remote_irr[i].swap(0, Ordering::Acquire));
+        let tmr = core::array::from_fn(|i| entry.remote_tmr[i].load(Ordering::Acquire) & irr[i]);
+        (irr, tmr)
+    }
+
+    /// Send an INIT to the local APIC of `vcpu_id`, and kick the vCPU so that it resets it.
+    pub(crate) fn post_init(&self, vcpu_id: u32) {
+        if let Some(entry) = self.dests.get(vcpu_id as usize) {
+            entry.init_pending.store(true, Ordering::Release);
+            self.kick(vcpu_id as _);
+        }
+    }
+
+    /// Take the INIT sent to `vcpu_id` with [`Self::post_init`], if any.
+    pub(crate) fn take_init(&self, vcpu_id: u32) -> bool {
+        self.dests[vcpu_id as usize]
+            .init_pending
+            .swap(false, Ordering::Acquire)
+    }
+
+    /// Post fixed interrupts sent to `vcpu_id` from now on, notifying its CPU with `nv` at `ndst`.
+    pub(crate) fn enable_posted(&self, vcpu_id: u32, nv: u8, ndst: u32) -> AxResult {
+        if self.pi_notifier.is_none() {
+            warn!("[VLAPIC] posted interrupts need a notifier on the APIC bus");
+            return Err(AxError::BadState);
+        }
+        let entry = &self.dests[vcpu_id as usize];
+        entry.pi_desc.set_notification(nv, ndst);
+        entry.posted.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Stop posting interrupts sent to `vcpu_id`. Requests already in its PIR remain there.
+    pub(crate) fn disable_posted(&self, vcpu_id: u32) {
+        self.dests[vcpu_id as usize]
+            .posted
+            .store(false, Ordering::Release);
+    }
+
+    /// Deliver a fixed interrupt with `vector` to the local APIC of another vCPU `vcpu_id`.
+    ///
+    /// 30.6 Posted-Interrupt Processing
+    /// If the target uses posted interrupts, the vector is set in its PIR and the notification vector is sent to its
+    /// CPU, unless suppressed.
+    ///
+    /// Level-triggered interrupts need the TMR to be set along with the IRR, which the target does itself: like
+    /// interrupts to targets without posted interrupts, they are recorded with [`Self::post_remote`], and the target
+    /// is kicked to accept them.
+    ///
+    /// If the target uses AVIC, the vector is set in the IRR of its backing page, see [`Self::kick_avic`].
+    pub(crate) fn deliver_fixed(&self, vcpu_id: usize, vector: u8, level: bool) {
+        if !level
+            && self
+                .with_avic_irr(vcpu_id, vector, |irr| {
+                    irr.fetch_or(1 << (vector & 0x1f), Ordering::SeqCst)
+                })
+                .is_some()
+        {
+            self.kick_avic(vcpu_id, vector);
+            return;
+        }
+        if let Some(entry) = self.dests.get(vcpu_id)
+            && !level
+            && entry.posted.load(Ordering::Acquire)
+        {
+            if entry.pi_desc.post(vector)
+                && let Some(notify) = self.pi_notifier
+            {
+                notify(entry.pi_desc.ndst(), entry.pi_desc.nv());
+            }
+            return;
+        }
+        self.post_remote(vcpu_id as _, vector, level);
+        self.kick(vcpu_id);
+    }
+
+    /// Returns whether the logical destination `dest` selects the local APIC of `vcpu_id`.
+    fn is_dest_field_matched(&self, vcpu_id: usize, dest: u32) -> AxResult<bool> {
+        let entry = &self.dests[vcpu_id];
+        let mut ret = false;
+
+        let ldr = entry.ldr.load(Ordering::Acquire);
+
+        if entry.x2apic.load(Ordering::Acquire) {
+            /*
+             * In x2APIC mode the MDA is made of a 16-bit cluster ID in
+             * dest[31:16] and a 16-bit bitmask in dest[15:0], matched
+             * against the cluster and logical ID in the LDR.
+             */
+            let cluster_id = ldr >> 16;
+            let logical_id = ldr & 0xffff;
+            let dest_cluster_id = dest >> 16;
+            let dest_logical_id = dest & 0xffff;
+            if (cluster_id == dest_cluster_id) && ((logical_id & dest_logical_id) != 0) {
+                ret = true;
+            }
+        } else {
+            match DESTINATION_FORMAT::Model
+                .read_as_enum::<APICDestinationFormat>(entry.dfr.load(Ordering::Acquire))
+                .ok_or(AxError::InvalidData)?
+            {
+                APICDestinationFormat::Flat => {
+                    /*
+                     * In the "Flat Model" the MDA is interpreted as an 8-bit wide
+                     * bitmask. This model is available in the xAPIC mode only.
+                     */
+                    let logical_id = ldr >> 24;
+                    let dest_logical_id = dest & 0xff;
+                    if logical_id & dest_logical_id != 0 {
+                        ret = true;
+                    }
+                }
+                APICDestinationFormat::Cluster => {
+                    /*
+                     * In the "Cluster Model" the MDA is used to identify a
+                     * specific cluster and a set of APICs in that cluster.
+                     */
+                    let logical_id = (ldr >> 24) & 0xf;
+                    let cluster_id = ldr >> 28;
+                    let dest_logical_id = dest & 0xf;
+                    let dest_cluster_id = (dest >> 4) & 0xf;
+                    if (cluster_id == dest_cluster_id) && ((logical_id & dest_logical_id) != 0) {
+                        ret = true;
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// 11.6.2 Determining IPI Destination
+    /// This function populates 'dmask' with the set of vcpus that match the
+    /// addressing specified by the (dest, phys, lowprio) tuple.
+    ///
+    /// Each local APIC compares `dest` with its own APIC ID, or with its own LDR and DFR in logical mode.
+    /// Lowest priority delivery is approximated by the lowest numbered vCPU in the destination set.
+    pub(crate) fn calculate_dest(
+        &self,
+        is_broadcast: bool,
+        dest: u32,
+        is_phys: bool,
+        lowprio: bool,
+    ) -> AxResult<VCpuSet> {
+        let mut dmask = VCpuSet::new(self.vcpu_num());
+
+        if is_broadcast {
+            // Broadcast in both logical and physical modes.
+            dmask = VCpuSet::full(self.vcpu_num());
+        } else if is_phys {
+            // Physical mode: "dest" is local APIC ID.
+            // Todo: distinguish between APIC ID and vCPU ID.
+            dmask.insert(dest as usize);
+        } else {
+            // Logical mode: "dest" is message destination addr
+            // to be compared with the logical APIC ID in LDR.
+            for i in 0..self.vcpu_num() {
+                if self.is_dest_field_matched(i, dest)? {
+                    dmask.insert(i);
+                }
+            }
+        }
+
+        if lowprio && let Some(first) = dmask.first() {
+            // Refer to 11.6.2.4 Lowest Priority Delivery Mode.
+            dmask = VCpuSet::new(self.vcpu_num());
+            dmask.insert(first);
+        }
+
+        Ok(dmask)
+    }
+
+    /// 11.11 Message Signalled Interrupts
+    /// Deliver the interrupt message written by a device to the MSI address `addr` with `data`.
+    ///
+    /// The destination is decoded as for IPIs, with address bits 11:5 taken as bits 14:8 of the destination ID
+    /// (the extended destination ID for x2APIC). The redirection hint selects lowest priority delivery in logical
+    /// destination mode. Fixed and lowest priority interrupts are delivered as IPIs from other vCPUs, i.e. posted or
+    /// recorded for the targets, which are kicked with the [`VCpuKicker`]. NMIs are delivered through
+    /// [`axvisor_api::vmm::inject_interrupt`] with vector 2.
+    pub fn deliver_msi(&self, addr: u64, data: u32) -> AxResult {
+        let address = MsiAddressRegisterLocal::new(addr as u32);
+        let data = MsiDataRegisterLocal::new(data);
+
+        if addr >> 32 != 0 || !address.matches_all(MSI_ADDRESS::FixedAddress::Interrupt) {
+            warn!("[VLAPIC] invalid MSI address {addr:#x}");
+            return Err(AxError::InvalidInput);
+        }
+        if address.is_set(MSI_ADDRESS::InterruptFormat) {
+            warn!("[VLAPIC] remappable MSI format {addr:#x} do not support");
+            return Err(AxError::Unsupported);
+        }
+
+        let dest = address.read(MSI_ADDRESS::DestinationID)
+            | (address.read(MSI_ADDRESS::ExtendedDestinationID) << 8);
+        let is_phys = address.matches_all(MSI_ADDRESS::DestinationMode::Physical);
+        let rh = address.is_set(MSI_ADDRESS::RedirectionHint);
+        let vec = data.read(MSI_DATA::Vector);
+        let mode = data
+            .read_as_enum::<MSI_DATA::DeliveryMode::Value>(MSI_DATA::DeliveryMode)
+            .ok_or(AxError::InvalidData)?;
+
+        if data.matches_all(MSI_DATA::TriggerMode::Level + MSI_DATA::Level::DeAssert) {
+            // Nothing to do for the de-assertion of a level-triggered interrupt.
+            return Ok(());
+        }
+
+        let lowprio = mode == MSI_DATA::DeliveryMode::Value::LowestPriority || (rh && !is_phys);
+        let dmask = self.calculate_dest(dest == XAPIC_BROADCAST_DEST_ID, dest, is_phys, lowprio)?;
+
+        debug!(
+            "[VLAPIC] MSI addr {addr:#x} data {:#x}: dest {dest:#x} dmask {dmask:?}",
+            data.get()
+        );
+
+        for i in dmask.iter() {
+            match mode {
+                MSI_DATA::DeliveryMode::Value::Fixed
+                | MSI_DATA::DeliveryMode::Value::LowestPriority => {
+                    if vec < 16 {
+                        debug!("[VLAPIC] Ignoring MSI with invalid vector {vec:#x}");
+                        break;
+                    }
+                    self.deliver_fixed(i, vec as u8, data.is_set(MSI_DATA::TriggerMode));
+                }
+                MSI_DATA::DeliveryMode::Value::NMI => {
+                    vmm::inject_interrupt(self.vm_id, i, NMI_VECTOR);
+                }
+                _ => {
+                    warn!("[VLAPIC] MSI with delivery mode {mode:?} do not support");
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{ApicBus, WireMode};
+
+    #[test]
+    fn test_wire_mode_switch() {
+        let bus = ApicBus::new(1, 4).unwrap();
+
+        // Reset state is PIC INTR wired to the processor
+        assert_eq!(bus.wire_mode(), WireMode::Intr);
+
+        // LINT0 ExtINT unmasked
+        assert!(bus.switch_wire_mode(&[WireMode::Intr, WireMode::Null], WireMode::Lapic));
+        assert_eq!(bus.wire_mode(), WireMode::Lapic);
+
+        // Transition not allowed from the current mode
+        assert!(!bus.switch_wire_mode(&[WireMode::Null], WireMode::Intr));
+        assert_eq!(bus.wire_mode(), WireMode::Lapic);
+
+        // LINT0 ExtINT masked
+        assert!(bus.switch_wire_mode(&[WireMode::Lapic], WireMode::Null));
+        assert_eq!(bus.wire_mode(), WireMode::Null);
+    }
+
+    #[test]
+    fn test_deliver_fixed_level() {
+        use crate::test_utils::{kick_vcpu, take_injected, take_kicked};
+
+        let mut bus = ApicBus::new(1, 2).unwrap();
+        bus.set_vcpu_kicker(kick_vcpu);
+
+        // The trigger mode is recorded with the vector, for the target to set the TMR along with the IRR
+        bus.deliver_fixed(1, 0x41, true);
+        bus.deliver_fixed(1, 0x82, false);
+        assert_eq!(take_kicked(), [(1, 1), (1, 1)]);
+        assert_eq!(take_injected(), []);
+        assert_eq!(
+            bus.take_remote(1),
+            ([0, 1 << 1, 1 << 2, 0], [0, 1 << 1, 0, 0])
+        );
+        assert_eq!(bus.take_remote(0), ([0; 4], [0; 4]));
+    }
+
+    #[test]
+    fn test_remote_read_apic_page() {
+        let bus = ApicBus::new(1, 2).unwrap();
+        let mut page = [0u32; 1024];
+        page[0x2 * 4] = 1 << 24; // ID
+        page[0x8 * 4] = 0x20; // TPR
+
+        // No page is published yet
+        assert_eq!(bus.read_apic_page(1, 0x8), None);
+
+        bus.set_apic_page(1, page.as_mut_ptr());
+        assert_eq!(bus.read_apic_page(1, 0x2), Some(1 << 24));
+        assert_eq!(bus.read_apic_page(1, 0x8), Some(0x20));
+        // Out of the page, or of the bus
+        assert_eq!(bus.read_apic_page(1, 0x100), None);
+        assert_eq!(bus.read_apic_page(2, 0x8), None);
+
+        bus.set_apic_page(1, core::ptr::null_mut());
+        assert_eq!(bus.read_apic_page(1, 0x8), None);
+    }
+
+    #[test]
+    fn test_avic_deliver_fixed() {
+        use axaddrspace::HostPhysAddr;
+        use core::sync::atomic::{AtomicU32, Ordering};
+
+        static DOORBELL: AtomicU32 = AtomicU32::new(0);
+        fn doorbell(host_apic_id: u32) {
+            DOORBELL.store(host_apic_id, Ordering::Relaxed);
+        }
+
+        let mut bus = ApicBus::new(1, 2).unwrap();
+        bus.enable_avic(doorbell);
+        let mut page = [0u32; 1024];
+        bus.set_apic_page(1, page.as_mut_ptr());
+        bus.add_avic_dest(1, HostPhysAddr::from_usize(0x1000))
+            .unwrap();
+
+        // Running: the vector is set in the IRR of the backing page, and the doorbell rung
+        bus.set_avic_running(1, Some(7));
+        bus.deliver_fixed(1, 0x41, false);
+        assert_eq!(page[0x22 * 4], 1 << 1);
+        assert_eq!(DOORBELL.load(Ordering::Relaxed), 7);
+
+        // Not running: the vector is taken back from the IRR and recorded for the vCPU
+        bus.set_avic_running(1, None);
+        bus.deliver_fixed(1, 0x42, false);
+        assert_eq!(page[0x22 * 4], 1 << 1);
+        assert_eq!(bus.take_remote(1), ([0, 1 << 2, 0, 0], [0; 4]));
+
+        // Level-triggered interrupts are not delivered through AVIC
+        bus.set_avic_running(1, Some(7));
+        bus.deliver_fixed(1, 0x43, true);
+        assert_eq!(page[0x22 * 4], 1 << 1);
+
+        bus.remove_avic_dest(1);
+        bus.deliver_fixed(1, 0x44, false);
+        assert_eq!(page[0x22 * 4], 1 << 1);
+        bus.set_apic_page(1, core::ptr::null_mut());
+    }
+
+    #[test]
+    fn test_x2apic_cluster_dest() {
+        use crate::consts::x2apic::x2apic_ldr;
+
+        let bus = ApicBus::new(1, 40).unwrap();
+        for id in 0..40 {
+            bus.update_dest(id, x2apic_ldr(id), 0, true);
+        }
+
+        let dest = |dest| {
+            bus.calculate_dest(false, dest, false, false)
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        };
+
+        // Cluster 0, logical IDs 0 and 3
+        assert_eq!(dest(0x0000_0009), [0, 3]);
+        // Cluster 2, logical ID 1
+        assert_eq!(dest(0x0002_0002), [33]);
+        // The same bitmap in another cluster
+        assert_eq!(dest(0x0001_0009), [16, 19]);
+        // Non-existent cluster
+        assert_eq!(dest(0x0005_ffff), []);
+    }
+
+    #[test]
+    fn test_msi_address_validation() {
+        let bus = ApicBus::new(1, 4).unwrap();
+
+        // Outside of the interrupt message area
+        assert!(bus.deliver_msi(0xFED0_0000, 0x30).is_err());
+        assert!(bus.deliver_msi(0x1_FEE0_0000, 0x30).is_err());
+        // Remappable format
+        assert!(bus.deliver_msi(0xFEE0_0010, 0x30).is_err());
+    }
+}
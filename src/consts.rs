@@ -70,6 +70,8 @@ macro_rules! define_index_enum {
 define_index_enum!(ISRIndex);
 define_index_enum!(TMRIndex);
 define_index_enum!(IRRIndex);
+define_index_enum!(IERIndex);
+define_index_enum!(ExtLvtIndex);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(clippy::upper_case_acronyms)]
@@ -129,6 +131,16 @@ pub enum ApicRegOffset {
     /// Self IPI register 0x3F.
     /// Available only in x2APIC mode.
     SelfIPI,
+    /// Extended APIC Feature register 0x40, AMD only.
+    ExtFeature,
+    /// Extended APIC Control register 0x41, AMD only.
+    ExtControl,
+    /// Specific End Of Interrupt register 0x42, AMD only.
+    SEOI,
+    /// Interrupt Enable register 0x48..=0x4F, AMD only.
+    IER(IERIndex),
+    /// Extended LVT register 0x50..=0x53, AMD only.
+    ExtLvt(ExtLvtIndex),
 }
 
 impl ApicRegOffset {
@@ -161,6 +173,11 @@ impl ApicRegOffset {
             0x39 => ApicRegOffset::TimerCurCount,
             0x3E => ApicRegOffset::TimerDivConf,
             0x3F => ApicRegOffset::SelfIPI,
+            0x40 => ApicRegOffset::ExtFeature,
+            0x41 => ApicRegOffset::ExtControl,
+            0x42 => ApicRegOffset::SEOI,
+            0x48..=0x4F => ApicRegOffset::IER(IERIndex::from(value - 0x48)),
+            0x50..=0x53 => ApicRegOffset::ExtLvt(ExtLvtIndex::from(value - 0x50)),
             _ => panic!("Invalid APIC register offset"),
         }
     }
@@ -196,6 +213,11 @@ impl core::fmt::Display for ApicRegOffset {
             ApicRegOffset::TimerCurCount => write!(f, "TimerCurCount"),
             ApicRegOffset::TimerDivConf => write!(f, "TimerDivConf"),
             ApicRegOffset::SelfIPI => write!(f, "SelfIPI"),
+            ApicRegOffset::ExtFeature => write!(f, "ExtFeature"),
+            ApicRegOffset::ExtControl => write!(f, "ExtControl"),
+            ApicRegOffset::SEOI => write!(f, "SEOI"),
+            ApicRegOffset::IER(index) => write!(f, "{index:?}"),
+            ApicRegOffset::ExtLvt(index) => write!(f, "{index:?}"),
         }
     }
 }
@@ -212,11 +234,17 @@ pub const RESET_LVT_REG: u32 = APIC_LVT_M;
 /// - Address: FEE0 00F0H
 /// - Value after reset: 0000 00FFH
 pub const RESET_SPURIOUS_INTERRUPT_VECTOR: u32 = 0x0000_00FF;
+/// 11.6.2.2 Logical Destination Mode
+/// Figure 11-14. Destination Format Register (DFR)
+/// - Value after reset: FFFF FFFFH (flat model)
+pub const RESET_DFR: u32 = 0xFFFF_FFFF;
 
-#[allow(dead_code)]
 pub const LAPIC_TRIG_LEVEL: bool = true;
 pub const LAPIC_TRIG_EDGE: bool = false;
 
+/// The vector passed to [`axvisor_api::vmm::inject_interrupt`] for an NMI, i.e. the NMI exception vector.
+pub const NMI_VECTOR: u8 = 2;
+
 pub mod xapic {
     use axaddrspace::GuestPhysAddr;
 
@@ -244,6 +272,13 @@ pub mod x2apic {
     /// in both logical destination and physical destination modes.
     pub const X2APIC_BROADCAST_DEST_ID: u32 = 0xFFFF_FFFF;
 
+    /// 11.12.10.2 Deriving Logical x2APIC ID from the Local x2APIC ID
+    /// The logical x2APIC ID in the LDR is read-only, made of the cluster ID x2APIC ID[19:4] in bits 31:16, and
+    /// of the bit 1 << x2APIC ID[3:0] in the 16-bit logical ID of bits 15:0.
+    pub const fn x2apic_ldr(x2apic_id: u32) -> u32 {
+        ((x2apic_id >> 4) << 16) | (1 << (x2apic_id & 0xf))
+    }
+
     pub(crate) const fn x2apic_msr_access_reg(addr: SysRegAddr) -> ApicRegOffset {
         ApicRegOffset::from(addr.addr() - X2APIC_MSE_REG_BASE)
     }
@@ -0,0 +1,210 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversion between [`LapicState`] and the `kvm_lapic_state` register page of the KVM_GET_LAPIC and
+//! KVM_SET_LAPIC ioctls, as found in QEMU/KVM snapshots.
+
+use axvisor_api::time::{nanos_to_ticks, ticks_to_nanos};
+
+use crate::consts::x2apic::x2apic_ldr;
+use crate::regs::{APIC_BASE, ApicBaseRegisterMsr};
+use crate::state::{
+    LAPIC_EXT_REG_SLOTS, LAPIC_REG_SLOTS, LAPIC_STATE_VERSION, LapicState, TimerState,
+};
+use crate::timer::divide_shift;
+
+/// The size of the register page in `struct kvm_lapic_state`.
+pub const KVM_APIC_REG_SIZE: usize = 0x400;
+
+// Register offsets on the page, named as in KVM.
+const APIC_ID: usize = 0x20;
+const APIC_LDR: usize = 0xD0;
+const APIC_SPIV: usize = 0xF0;
+const APIC_ISR: usize = 0x100;
+const APIC_LVTCMCI: usize = 0x2F0;
+const APIC_LVTT: usize = 0x320;
+const APIC_LVTTHMR: usize = 0x330;
+const APIC_LVTPC: usize = 0x340;
+const APIC_LVT0: usize = 0x350;
+const APIC_LVT1: usize = 0x360;
+const APIC_LVTERR: usize = 0x370;
+const APIC_TMICT: usize = 0x380;
+const APIC_TMCCT: usize = 0x390;
+const APIC_TDCR: usize = 0x3E0;
+
+/// `struct kvm_lapic_state`: the first 1 KiB of the local APIC register page, with each register in the low 4 bytes
+/// of its 16-byte slot, little-endian.
+///
+/// The IA32_APIC_BASE and MSR_KVM_PV_EOI_EN MSRs are not part of it, KVM saves them with the other MSRs.
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvmLapicState {
+    /// The register page, as `char regs[KVM_APIC_REG_SIZE]`.
+    pub regs: [u8; KVM_APIC_REG_SIZE],
+}
+
+impl Default for KvmLapicState {
+    fn default() -> Self {
+        Self {
+            regs: [0; KVM_APIC_REG_SIZE],
+        }
+    }
+}
+
+impl KvmLapicState {
+    fn get_reg(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.regs[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn set_reg(&mut self, offset: usize, val: u32) {
+        self.regs[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+    }
+}
+
+impl LapicState {
+    /// Convert a `kvm_lapic_state` saved with the IA32_APIC_BASE MSR value `apic_base`.
+    ///
+    /// In x2APIC mode, KVM stores the x2APIC ID in bits 31:0 of the ID register only if the VMM enabled
+    /// `KVM_X2APIC_API_USE_32BIT_IDS`, which `x2apic_format` tells. Otherwise it stores it in bits 31:24 as in
+    /// xAPIC mode, which is only correct for IDs below 256. The LDR is derived from the ID in x2APIC mode.
+    ///
+    /// KVM keeps the current count of the timer in TMCCT, which is turned into the time left until the deadline.
+    /// The internal states KVM does not save start out cleared: the LINT pins are deasserted, and no vector is routed
+    /// as level-triggered. KVM does not implement the AMD extended APIC register space either.
+    /// MSR_KVM_PV_EOI_EN is saved by KVM with the other MSRs, and should be copied to [`Self::pv_eoi_msr`].
+    pub fn from_kvm(kvm: &KvmLapicState, apic_base: u64, x2apic_format: bool) -> Self {
+        let mut regs: [u32; LAPIC_REG_SLOTS] =
+            core::array::from_fn(|index| kvm.get_reg(index << 4));
+
+        if is_x2apic_enabled(apic_base) {
+            if !x2apic_format {
+                regs[APIC_ID >> 4] >>= 24;
+            }
+            regs[APIC_LDR >> 4] = x2apic_ldr(regs[APIC_ID >> 4]);
+        }
+
+        // The ISR is spread across 8 slots, each holding 32 vectors.
+        let isrv = (0..8)
+            .rev()
+            .map(|i| (i, regs[(APIC_ISR >> 4) + i]))
+            .find(|&(_, isr)| isr != 0)
+            .map_or(0, |(i, isr)| (i as u32) << 5 | (31 - isr.leading_zeros()));
+
+        let initial_count = regs[APIC_TMICT >> 4];
+        let current_count = regs[APIC_TMCCT >> 4];
+        let divide_config = regs[APIC_TDCR >> 4];
+        let remaining_ns = (initial_count > 0 && current_count > 0)
+            .then(|| ticks_to_nanos((current_count as u64) << divide_shift(divide_config)));
+
+        Self {
+            version: LAPIC_STATE_VERSION,
+            apic_base,
+            regs,
+            ext_regs: [0; LAPIC_EXT_REG_SLOTS],
+            svr: regs[APIC_SPIV >> 4],
+            lvt: [
+                APIC_LVTCMCI,
+                APIC_LVTT,
+                APIC_LVTTHMR,
+                APIC_LVTPC,
+                APIC_LVT0,
+                APIC_LVT1,
+                APIC_LVTERR,
+            ]
+            .map(|offset| regs[offset >> 4]),
+            esr_pending: 0,
+            isrv,
+            lint_asserted: [false; 2],
+            level_vectors: [0; 4],
+            timer: TimerState {
+                lvt: regs[APIC_LVTT >> 4],
+                initial_count,
+                divide_config,
+                remaining_ns,
+            },
+            pv_eoi_msr: 0,
+        }
+    }
+
+    /// Convert to a `kvm_lapic_state`, to be loaded with the IA32_APIC_BASE MSR value [`Self::apic_base`].
+    ///
+    /// The ID register is encoded as described in [`Self::from_kvm`]. The LDR is derived from the ID in x2APIC mode,
+    /// and TMCCT holds the current count of the timer, as KVM_GET_LAPIC reports them.
+    pub fn to_kvm(&self, x2apic_format: bool) -> KvmLapicState {
+        let mut regs = self.regs;
+
+        if is_x2apic_enabled(self.apic_base) {
+            regs[APIC_LDR >> 4] = x2apic_ldr(regs[APIC_ID >> 4]);
+            if !x2apic_format {
+                regs[APIC_ID >> 4] <<= 24;
+            }
+        }
+
+        regs[APIC_TMCCT >> 4] = self.timer.remaining_ns.map_or(0, |remaining_ns| {
+            (nanos_to_ticks(remaining_ns) >> divide_shift(self.timer.divide_config)) as u32
+        });
+
+        let mut kvm = KvmLapicState::default();
+        for (index, &val) in regs.iter().enumerate() {
+            kvm.set_reg(index << 4, val);
+        }
+        kvm
+    }
+}
+
+fn is_x2apic_enabled(apic_base: u64) -> bool {
+    let apic_base = ApicBaseRegisterMsr::new(apic_base);
+    apic_base.is_set(APIC_BASE::XAPIC_ENABLED) && apic_base.is_set(APIC_BASE::X2APIC_Enabled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XAPIC_ENABLED: u64 = 0xFEE0_0800;
+    const X2APIC_ENABLED: u64 = 0xFEE0_0C00;
+
+    #[test]
+    fn test_kvm_lapic_state_x2apic_id() {
+        let mut kvm = KvmLapicState::default();
+        kvm.set_reg(APIC_ID, 3 << 24);
+        kvm.set_reg(APIC_SPIV, 0x1FF);
+        kvm.set_reg(APIC_ISR + 0x30, 1 << 2); // vector 0x62 in service
+        kvm.set_reg(APIC_LVT0, 0x700);
+
+        // xAPIC mode: the ID stays in bits 31:24
+        let state = LapicState::from_kvm(&kvm, XAPIC_ENABLED, true);
+        assert_eq!(state.regs[APIC_ID >> 4], 3 << 24);
+        assert_eq!(state.svr, 0x1FF);
+        assert_eq!(state.isrv, 0x62);
+        assert_eq!(state.lvt[4], 0x700);
+        assert_eq!(state.timer.remaining_ns, None);
+        assert_eq!(state.to_kvm(true), kvm);
+
+        // x2APIC mode with the legacy format: the ID is moved to bits 31:0, and the LDR derived from it
+        let state = LapicState::from_kvm(&kvm, X2APIC_ENABLED, false);
+        assert_eq!(state.regs[APIC_ID >> 4], 3);
+        assert_eq!(state.regs[APIC_LDR >> 4], 1 << 3);
+        let exported = state.to_kvm(false);
+        assert_eq!(exported.get_reg(APIC_ID), 3 << 24);
+        assert_eq!(exported.get_reg(APIC_LDR), 1 << 3);
+
+        // x2APIC mode with 32-bit IDs: the ID is taken as is
+        kvm.set_reg(APIC_ID, 0x123);
+        let state = LapicState::from_kvm(&kvm, X2APIC_ENABLED, true);
+        assert_eq!(state.regs[APIC_ID >> 4], 0x123);
+        assert_eq!(state.regs[APIC_LDR >> 4], (0x12 << 16) | (1 << 3));
+        assert_eq!(state.to_kvm(true).get_reg(APIC_ID), 0x123);
+    }
+}
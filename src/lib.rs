@@ -21,23 +21,32 @@ extern crate alloc;
 #[macro_use]
 extern crate log;
 
+mod apicv;
+mod avic;
+mod bus;
 mod consts;
+mod kvm;
+mod posted;
+mod profile;
+mod pv_eoi;
 mod regs;
+mod state;
+#[cfg(test)]
+mod test_utils;
 mod timer;
 mod utils;
 mod vlapic;
 
-use core::cell::UnsafeCell;
+use alloc::sync::Arc;
+
+use spin::{Mutex, MutexGuard};
 
 use axerrno::AxResult;
-use axvisor_api::{
-    memory,
-    vmm::{VCpuId, VMId},
-};
-use memory_addr::{AddrRange, PAGE_SIZE_4K};
+use axvisor_api::vmm::VCpuId;
+use memory_addr::AddrRange;
 
 use axaddrspace::{
-    GuestPhysAddr, HostPhysAddr, HostVirtAddr,
+    GuestPhysAddr, HostPhysAddr,
     device::{AccessWidth, SysRegAddr, SysRegAddrRange},
 };
 use axdevice_base::{BaseDeviceOps, EmuDeviceType};
@@ -46,44 +55,66 @@ use crate::consts::x2apic::x2apic_msr_access_reg;
 use crate::consts::xapic::xapic_mmio_access_reg_offset;
 use crate::vlapic::VirtualApicRegs;
 
-#[repr(align(4096))]
-struct APICAccessPage([u8; PAGE_SIZE_4K]);
-
-static VIRTUAL_APIC_ACCESS_PAGE: APICAccessPage = APICAccessPage([0; PAGE_SIZE_4K]);
+pub use crate::apicv::{ApicvFeatures, IA32_TSC_DEADLINE, X2ApicMsrIntercepts};
+pub use crate::avic::{AVIC_DOORBELL_MSR, AvicDoorbell};
+pub use crate::bus::{
+    ApicBus, GuestPhysTranslator, IoApicEoiHandler, PostedInterruptNotifier, VCpuKicker, WireMode,
+};
+pub use crate::kvm::{KVM_APIC_REG_SIZE, KvmLapicState};
+pub use crate::profile::{AmdExtApic, LapicProfile};
+pub use crate::pv_eoi::MSR_KVM_PV_EOI_EN;
+pub use crate::state::{LAPIC_STATE_VERSION, LapicState, TimerState};
+pub use crate::timer::TimerBackend;
+pub use crate::vlapic::LintPin;
 
 /// A emulated local APIC device.
+///
+/// The registers are owned by the vCPU of the local APIC: they are only accessed from its register accesses and VM
+/// exits, so the lock around them is not contended. Other CPUs only deliver interrupts, through
+/// [`Self::accept_interrupt`] or the posted-interrupt descriptor, which record them with atomic operations and
+/// never take the lock. The vCPU moves them to the IRR the next time it accesses the local APIC.
 pub struct EmulatedLocalApic {
-    vlapic_regs: UnsafeCell<VirtualApicRegs>,
+    vlapic_regs: Mutex<VirtualApicRegs>,
+    bus: Arc<ApicBus>,
+    vcpu_id: VCpuId,
 }
 
 impl EmulatedLocalApic {
-    /// Create a new `EmulatedLocalApic`.
-    pub fn new(vm_id: VMId, vcpu_id: VCpuId) -> Self {
-        EmulatedLocalApic {
-            vlapic_regs: UnsafeCell::new(VirtualApicRegs::new(vm_id, vcpu_id)),
-        }
-    }
-
-    fn get_vlapic_regs(&self) -> &VirtualApicRegs {
-        unsafe { &*self.vlapic_regs.get() }
+    /// Create a new `EmulatedLocalApic` for `vcpu_id`, attached to the APIC bus of its VM.
+    ///
+    /// Fails with `InvalidInput` if `vcpu_id` is not less than the number of vCPUs of the APIC bus.
+    pub fn new(bus: Arc<ApicBus>, vcpu_id: VCpuId) -> AxResult<Self> {
+        Ok(EmulatedLocalApic {
+            vlapic_regs: Mutex::new(VirtualApicRegs::new(bus.clone(), vcpu_id)?),
+            bus,
+            vcpu_id,
+        })
     }
 
-    #[allow(clippy::mut_from_ref)] // SAFETY: get_mut_vlapic_regs is never called concurrently.
-    fn get_mut_vlapic_regs(&self) -> &mut VirtualApicRegs {
-        unsafe { &mut *self.vlapic_regs.get() }
+    /// Lock the registers, moving the interrupts accepted from other CPUs to the IRR.
+    fn vlapic_regs(&self) -> MutexGuard<'_, VirtualApicRegs> {
+        let mut regs = self.vlapic_regs.lock();
+        regs.sync_irr();
+        regs
     }
 }
 
+// The local APIC is shared by the CPUs delivering interrupts to its vCPU.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<EmulatedLocalApic>();
+};
+
 impl EmulatedLocalApic {
     /// APIC-access address (64 bits).
     /// This field contains the physical address of the 4-KByte APIC-access page.
     /// If the “virtualize APIC accesses” VM-execution control is 1,
     /// access to this page may cause VM exits or be virtualized by the processor.
     /// See Section 30.4.
-    pub fn virtual_apic_access_addr() -> HostPhysAddr {
-        memory::virt_to_phys(HostVirtAddr::from_usize(
-            VIRTUAL_APIC_ACCESS_PAGE.0.as_ptr() as usize,
-        ))
+    ///
+    /// The page belongs to the [`ApicBus`] of the VM, and is shared by all its vCPUs.
+    pub fn virtual_apic_access_addr(&self) -> HostPhysAddr {
+        self.vlapic_regs().apic_access_addr()
     }
 
     /// Virtual-APIC address (64 bits).
@@ -91,7 +122,325 @@ impl EmulatedLocalApic {
     /// The processor uses the virtual-APIC page to virtualize certain accesses to APIC registers and to manage virtual interrupts;
     /// see Chapter 30.
     pub fn virtual_apic_page_addr(&self) -> HostPhysAddr {
-        self.get_vlapic_regs().virtual_apic_page_addr()
+        self.vlapic_regs().virtual_apic_page_addr()
+    }
+
+    /// Tell the local APIC which APIC virtualization VM-execution controls the VMM uses for this vCPU.
+    pub fn set_apicv_features(&self, features: ApicvFeatures) {
+        self.vlapic_regs().set_apicv_features(features);
+    }
+
+    /// Select the local APIC model presented to the guest through the Version register, see [`LapicProfile`].
+    ///
+    /// Should be called before the vCPU first runs. The LVT CMCI register is reserved if the profile does not
+    /// implement it, and so is the Suppress EOI Broadcasts bit of the SVR without EOI-broadcast suppression.
+    /// The AMD extended registers are only decoded with [`LapicProfile::amd_ext`], see [`AmdExtApic`].
+    pub fn set_profile(&self, profile: LapicProfile) {
+        self.vlapic_regs().set_profile(profile);
+    }
+
+    /// The x2APIC MSRs (800H–8FFH, and [`IA32_TSC_DEADLINE`]) to intercept in the VMX MSR bitmaps, for the current
+    /// APIC mode and the features set with [`Self::set_apicv_features`].
+    ///
+    /// Reads are passed through only with APIC-register virtualization, and writes only for the TPR, and for EOI and
+    /// SELF IPI with virtual-interrupt delivery. The result changes when the guest switches to x2APIC mode.
+    pub fn x2apic_msr_intercepts(&self) -> X2ApicMsrIntercepts {
+        self.vlapic_regs().x2apic_msr_intercepts()
+    }
+
+    /// TPR threshold (32 bits).
+    /// With the “use TPR shadow” VM-execution control and without “virtual-interrupt delivery”, this field should be
+    /// written to the VMCS before VM entry. Bits 3:0 are the priority class of the highest pending interrupt when it
+    /// is masked by the VTPR, so that a TPR-below-threshold VM exit happens as soon as the guest unmasks it.
+    /// See Section 30.1.2.
+    pub fn tpr_threshold(&self) -> u32 {
+        self.vlapic_regs().tpr_threshold()
+    }
+
+    /// Handle a TPR-below-threshold VM exit.
+    ///
+    /// The guest updated the VTPR on the virtual-APIC page without a VM exit, so the PPR is re-synchronized from it.
+    /// Returns the interrupt that is now pending, as [`Self::pending_interrupt`] does.
+    pub fn handle_tpr_below_threshold(&self) -> Option<u8> {
+        self.vlapic_regs().handle_tpr_below_threshold()
+    }
+
+    /// Guest interrupt status (16 bits).
+    /// With the “virtual-interrupt delivery” VM-execution control, this field holds RVI in its low byte and SVI in
+    /// its high byte, and should be written to the VMCS before VM entry. The processor then delivers pending
+    /// interrupts without [`Self::pending_interrupt`] and [`Self::acknowledge_interrupt`]. See Section 30.2.
+    pub fn guest_interrupt_status(&self) -> u16 {
+        self.vlapic_regs().guest_interrupt_status()
+    }
+
+    /// EOI-exit bitmap (256 bits), as the four 64-bit EOI-exit bitmap fields of the VMCS.
+    /// A bit is set for each vector whose EOI has side effects outside of the virtual-APIC page: level-triggered
+    /// vectors from the TMR, from LINT0, and from routes registered with [`Self::set_level_triggered_vector`].
+    /// Their EOIs cause EOI-induced VM exits, to be handled with [`Self::handle_virtual_eoi`]. See Section 30.1.4.
+    pub fn eoi_exit_bitmap(&self) -> [u64; 4] {
+        self.vlapic_regs().eoi_exit_bitmap()
+    }
+
+    /// Register `vector` as delivered level-triggered to this vCPU, e.g. by an I/O APIC redirection entry, so that
+    /// its EOI is included in [`Self::eoi_exit_bitmap`]. `level` false removes the route.
+    pub fn set_level_triggered_vector(&self, vector: u8, level: bool) {
+        self.vlapic_regs().set_level_triggered_vector(vector, level);
+    }
+
+    /// Handle an EOI-induced VM exit for `vector`, from the exit qualification.
+    ///
+    /// The EOI goes through the same path as a write to the EOI register, resetting the Remote IRR of LINT0 and
+    /// recomputing SVI and PPR after the processor cleared the ISR bit.
+    pub fn handle_virtual_eoi(&self, vector: u8) {
+        self.vlapic_regs().handle_virtual_eoi(vector as _);
+    }
+
+    /// Posted-interrupt descriptor address (64 bits).
+    /// This field contains the physical address of the 64-byte aligned posted-interrupt descriptor of this vCPU,
+    /// used if the “process posted interrupts” VM-execution control is 1. See Section 30.6.
+    pub fn posted_interrupt_desc_addr(&self) -> HostPhysAddr {
+        self.vlapic_regs().posted_interrupt_desc_addr()
+    }
+
+    /// Deliver fixed, edge-triggered interrupts sent by other vCPUs through the posted-interrupt descriptor.
+    ///
+    /// Senders set the vector in the PIR and call the [`PostedInterruptNotifier`] of the APIC bus with the
+    /// notification vector `nv` and destination `ndst`, which must match the VMCS and the physical CPU running this
+    /// vCPU. Fails if the APIC bus has no notifier.
+    pub fn enable_posted_interrupts(&self, nv: u8, ndst: u32) -> AxResult {
+        self.vlapic_regs().enable_posted_interrupts(nv, ndst)
+    }
+
+    /// Go back to recording interrupts from other vCPUs for [`Self::accept_interrupt`], and kicking this vCPU with
+    /// the [`VCpuKicker`] of the APIC bus.
+    pub fn disable_posted_interrupts(&self) {
+        self.vlapic_regs().disable_posted_interrupts();
+    }
+
+    /// Update the notification destination of posted interrupts after the vCPU moved to another physical CPU.
+    pub fn set_posted_interrupt_dest(&self, ndst: u32) {
+        self.vlapic_regs().set_posted_interrupt_dest(ndst);
+    }
+
+    /// Set or clear the Suppress Notification bit of the posted-interrupt descriptor, typically while the vCPU is
+    /// scheduled out. Interrupts are still posted, and picked up by [`Self::sync_posted_interrupts`].
+    pub fn suppress_posted_interrupt_notification(&self, suppress: bool) {
+        self.vlapic_regs()
+            .suppress_posted_interrupt_notification(suppress);
+    }
+
+    /// Let the processor deliver interrupts to this vCPU with AMD AVIC, using the virtual-APIC page returned by
+    /// [`Self::virtual_apic_page_addr`] as the APIC backing page, which has the same layout.
+    ///
+    /// The vCPU is entered in the physical and logical APIC ID tables of the APIC bus, which must have been created
+    /// with [`ApicBus::enable_avic`]. Fixed, edge-triggered interrupts from other vCPUs are then set in the IRR of the
+    /// backing page, and the CPU running the vCPU is notified through the [`AvicDoorbell`]. AVIC only virtualizes
+    /// the xAPIC mode, so the VMM should disable it when the guest switches to x2APIC mode.
+    pub fn enable_avic(&self) -> AxResult {
+        self.vlapic_regs().enable_avic()
+    }
+
+    /// Remove this vCPU from the AVIC APIC ID tables, going back to interrupts recorded as for
+    /// [`Self::accept_interrupt`], and the [`VCpuKicker`] of the APIC bus.
+    pub fn disable_avic(&self) {
+        self.vlapic_regs().disable_avic();
+    }
+
+    /// Tell AVIC whether the vCPU is running, with the physical APIC ID of the CPU it's loaded on, or `None` when it's
+    /// put. Interrupts sent to a vCPU that is not running are recorded as for [`Self::accept_interrupt`], and it's
+    /// woken up with the [`VCpuKicker`] of the APIC bus.
+    pub fn set_avic_running(&self, host_apic_id: Option<u32>) {
+        self.vlapic_regs().set_avic_running(host_apic_id);
+    }
+
+    /// Handle an AVIC_INCOMPLETE_IPI #VMEXIT, with EXITINFO1 and EXITINFO2 from the VMCB.
+    ///
+    /// IPIs the processor could not deliver are sent as for a write to the ICR: NMIs and INITs are delivered, while
+    /// SIPIs are dropped, see [`Self::take_pending_init`]. Targets that are not running are woken up.
+    pub fn handle_avic_incomplete_ipi(&self, exit_info1: u64, exit_info2: u64) -> AxResult {
+        self.vlapic_regs()
+            .handle_avic_incomplete_ipi(exit_info1, exit_info2)
+    }
+
+    /// Handle an AVIC_NOACCEL #VMEXIT, with EXITINFO1 from the VMCB. Returns whether it was handled.
+    ///
+    /// Trapped writes, which already updated the backing page, take effect as emulated writes do. Faulting accesses
+    /// return `false`, and the VMM should decode the instruction and emulate it as an MMIO access to this device.
+    pub fn handle_avic_unaccelerated_access(&self, exit_info1: u64) -> AxResult<bool> {
+        self.vlapic_regs()
+            .handle_avic_unaccelerated_access(exit_info1)
+    }
+
+    /// Move the interrupts pending in the PIR to the IRR. Call this before every VM entry when posted interrupts
+    /// are enabled, as the processor only processes the PIR on receipt of the notification vector.
+    ///
+    /// Returns whether any interrupt was pending in the PIR, or accepted from another CPU.
+    pub fn sync_posted_interrupts(&self) -> bool {
+        self.vlapic_regs.lock().sync_irr()
+    }
+
+    /// Accept a fixed interrupt with `vector`, e.g. one from a device model of the VMM. `level` selects
+    /// level-triggered (rather than edge-triggered) delivery, which sets the corresponding TMR bit.
+    ///
+    /// This can be called from any CPU without waiting for the vCPU: the vector is recorded atomically, and moved to
+    /// the IRR the next time the vCPU accesses the local APIC, e.g. with [`Self::pending_interrupt`]. Then a
+    /// software-disabled local APIC drops it, and illegal vectors (0 to 15) are rejected and recorded in the ESR.
+    pub fn accept_interrupt(&self, vector: u8, level: bool) {
+        self.bus.post_remote(self.vcpu_id as _, vector, level);
+    }
+
+    /// The highest priority interrupt that the local APIC would dispatch to the vCPU now, i.e. the highest vector
+    /// in the IRR whose priority class is above the processor priority.
+    ///
+    /// Interrupts pending in the IRR when the APIC is software-disabled are held and still reported here.
+    pub fn pending_interrupt(&self) -> Option<u8> {
+        self.vlapic_regs().pending_intr()
+    }
+
+    /// Acknowledge the highest priority pending interrupt when the vCPU run loop injects it into the guest, moving
+    /// it from the IRR to the ISR. Returns the vector to inject.
+    ///
+    /// This should only be called after [`Self::pending_interrupt`] reported an interrupt, typically once the guest
+    /// opens an interrupt window. If the guest raised its task priority in between so that the interrupt is masked,
+    /// the spurious-interrupt vector from the SVR is returned without touching the ISR, as on real hardware.
+    pub fn acknowledge_interrupt(&self) -> Option<u8> {
+        self.vlapic_regs().acknowledge_intr()
+    }
+
+    /// Drive the `pin` input of the local APIC to the electrical `level` (high if `true`).
+    ///
+    /// The interrupt is delivered according to the LVT entry of the pin: its polarity decides whether the level
+    /// asserts the pin, edge-triggered modes fire on the asserting transition, and level-triggered fixed interrupts
+    /// are not delivered again while the Remote IRR flag is set. NMIs are delivered through
+    /// [`axvisor_api::vmm::inject_interrupt`] with vector 2.
+    pub fn set_lint(&self, pin: LintPin, level: bool) {
+        self.vlapic_regs().set_lint(pin, level);
+    }
+
+    /// Drive the `pin` input high, e.g. when the 8259 PIC raises its INTR output.
+    pub fn assert_lint(&self, pin: LintPin) {
+        self.set_lint(pin, true);
+    }
+
+    /// Drive the `pin` input low.
+    pub fn deassert_lint(&self, pin: LintPin) {
+        self.set_lint(pin, false);
+    }
+
+    /// Returns whether an ExtINT interrupt is pending, i.e. a LINT pin in ExtINT delivery mode is asserted.
+    ///
+    /// The vector is then supplied by the virtual 8259 PIC instead of the local APIC: the vCPU run loop should
+    /// acknowledge the PIC and inject its vector, without touching the IRR or ISR. ExtINT interrupts are not subject
+    /// to the task priority. See [`ApicBus::wire_mode`] for whether the PIC output goes through the local APICs.
+    pub fn pending_extint(&self) -> bool {
+        self.vlapic_regs().pending_extint()
+    }
+
+    /// The value of the [`MSR_KVM_PV_EOI_EN`] MSR, for the guest to read.
+    pub fn pv_eoi_msr(&self) -> u64 {
+        self.vlapic_regs().pv_eoi_msr()
+    }
+
+    /// Handle a guest write to the [`MSR_KVM_PV_EOI_EN`] MSR, enabling or disabling KVM paravirtual EOI, which the
+    /// VMM advertises with KVM_FEATURE_PV_EOI in CPUID leaf 4000_0001H. Fails if the value is invalid, in which case
+    /// the VMM should inject #GP.
+    ///
+    /// The flag is mapped through the [`GuestPhysTranslator`] of the APIC bus once, when the MSR is written. It's
+    /// meant for guests without virtual-interrupt delivery, which virtualizes EOIs by itself.
+    pub fn write_pv_eoi_msr(&self, value: u64) -> AxResult {
+        self.vlapic_regs().write_pv_eoi_msr(value)
+    }
+
+    /// Perform the EOI the guest signalled by clearing the paravirtual EOI flag. Call this on the CPU running the
+    /// vCPU right after every VM exit, before handling it, when paravirtual EOI is enabled.
+    pub fn sync_pv_eoi_from_guest(&self) {
+        self.vlapic_regs.lock().sync_pv_eoi_from_guest();
+    }
+
+    /// Update the paravirtual EOI flag of the guest. Call this on the CPU running the vCPU right before every VM
+    /// entry, after injecting interrupts, when paravirtual EOI is enabled.
+    ///
+    /// The flag is set if the interrupt in service can be ended without a VM exit: the guest then clears it instead
+    /// of writing the EOI register, and the EOI is performed by [`Self::sync_pv_eoi_from_guest`] after the next VM
+    /// exit.
+    pub fn sync_pv_eoi_to_guest(&self) {
+        self.vlapic_regs.lock().sync_pv_eoi_to_guest();
+    }
+
+    /// Notify the local APIC that its vCPU has been migrated to another physical CPU.
+    ///
+    /// Host timers are registered on the CPU this is called on, so it must be called on the destination CPU, before
+    /// the vCPU runs there. Pending APIC timers are cancelled on the old CPU and re-registered here with their
+    /// deadlines preserved.
+    pub fn on_vcpu_migrate(&self) -> AxResult {
+        self.vlapic_regs().migrate_timer()
+    }
+
+    /// Select how the APIC timer waits for its deadline, see [`TimerBackend`].
+    ///
+    /// A running timer keeps its deadline when the backend is switched.
+    pub fn set_timer_backend(&self, backend: TimerBackend) {
+        self.vlapic_regs().set_timer_backend(backend);
+    }
+
+    /// The host tick at which the APIC timer expires next, or `None` if it is stopped.
+    ///
+    /// With [`TimerBackend::External`], the vCPU run loop should program the VMX-preemption timer (or a host hrtimer)
+    /// with this deadline before every VM entry, as it changes whenever the guest reprograms the timer.
+    pub fn timer_deadline(&self) -> Option<u64> {
+        self.vlapic_regs().timer_deadline()
+    }
+
+    /// Handle the expiry of the deadline returned by [`Self::timer_deadline`], used with [`TimerBackend::External`].
+    ///
+    /// Injects the timer interrupt unless it is masked, and re-arms a periodic timer. Returns whether an interrupt
+    /// was injected. Calling this before the deadline is reached does nothing.
+    pub fn handle_timer_expiry(&self) -> bool {
+        self.vlapic_regs().handle_timer_expiry()
+    }
+
+    /// Save the state of the local APIC, to snapshot the vCPU or migrate it to another host.
+    ///
+    /// The vCPU should be stopped, so that the guest does not change the virtual-APIC page meanwhile. Interrupts
+    /// already accepted from other CPUs are included, and the APIC timer is saved with the time left until its
+    /// deadline.
+    pub fn save(&self) -> LapicState {
+        self.vlapic_regs().save()
+    }
+
+    /// Restore the state of the local APIC returned by [`Self::save`], before the vCPU is resumed.
+    ///
+    /// Fails with [`AxError::Unsupported`](axerrno::AxError::Unsupported) if the state was saved with a different
+    /// [`LAPIC_STATE_VERSION`]. The APIC timer expires after the time that was left when the state was saved.
+    pub fn restore(&self, state: &LapicState) -> AxResult {
+        self.vlapic_regs().restore(state)
+    }
+
+    /// Reset the local APIC to its power-up state (SDM Vol. 3A, Section 11.4.7.1), as on a reset of the VM.
+    ///
+    /// The local APIC is enabled in xAPIC mode and software-disabled, all LVT entries are masked and the timer is
+    /// stopped. Pending and in-service interrupts are discarded.
+    pub fn reset_power_on(&self) -> AxResult {
+        self.vlapic_regs().reset_power_on()
+    }
+
+    /// Reset the local APIC on an INIT (SDM Vol. 3A, Sections 11.4.7.3 and 11.12.5.1).
+    ///
+    /// Same as [`Self::reset_power_on`], except that the APIC ID and the IA32_APIC_BASE MSR, including the x2APIC
+    /// mode, are preserved. INIT IPIs and INITs delivered through LINT0 or LINT1 do this on their own, see
+    /// [`Self::take_pending_init`].
+    pub fn reset_init(&self) -> AxResult {
+        self.vlapic_regs().reset_init()
+    }
+
+    /// Returns whether the local APIC was reset by an INIT IPI, or an INIT delivered through LINT0 or LINT1, since
+    /// the last call. The VMM should then put the vCPU in the wait-for-SIPI state: Start-Up IPIs are not emulated by
+    /// the local APIC.
+    ///
+    /// INITs from other vCPUs are processed the next time this vCPU accesses the local APIC, which they make happen
+    /// by kicking it with the [`VCpuKicker`] of the APIC bus.
+    pub fn take_pending_init(&self) -> bool {
+        self.vlapic_regs().take_init()
     }
 }
 
@@ -111,13 +460,13 @@ impl BaseDeviceOps<AddrRange<GuestPhysAddr>> for EmulatedLocalApic {
     fn handle_read(&self, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
         debug!("EmulatedLocalApic::handle_read: addr={addr:?}, width={width:?}");
         let reg_off = xapic_mmio_access_reg_offset(addr);
-        self.get_vlapic_regs().handle_read(reg_off, width)
+        self.vlapic_regs().handle_read(reg_off, width)
     }
 
     fn handle_write(&self, addr: GuestPhysAddr, width: AccessWidth, val: usize) -> AxResult {
         debug!("EmulatedLocalApic::handle_write: addr={addr:?}, width={width:?}, val={val:#x}");
         let reg_off = xapic_mmio_access_reg_offset(addr);
-        self.get_mut_vlapic_regs().handle_write(reg_off, val, width)
+        self.vlapic_regs().handle_write(reg_off, val, width)
     }
 }
 
@@ -137,12 +486,12 @@ impl BaseDeviceOps<SysRegAddrRange> for EmulatedLocalApic {
     fn handle_read(&self, addr: SysRegAddr, width: AccessWidth) -> AxResult<usize> {
         debug!("EmulatedLocalApic::handle_read: addr={addr:?}, width={width:?}");
         let reg_off = x2apic_msr_access_reg(addr);
-        self.get_vlapic_regs().handle_read(reg_off, width)
+        self.vlapic_regs().handle_read(reg_off, width)
     }
 
     fn handle_write(&self, addr: SysRegAddr, width: AccessWidth, val: usize) -> AxResult {
         debug!("EmulatedLocalApic::handle_write: addr={addr:?}, width={width:?}, val={val:#x}");
         let reg_off = x2apic_msr_access_reg(addr);
-        self.get_mut_vlapic_regs().handle_write(reg_off, val, width)
+        self.vlapic_regs().handle_write(reg_off, val, width)
     }
 }
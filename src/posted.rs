@@ -0,0 +1,137 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! 30.6 Posted-Interrupt Processing
+//! Posted-interrupt processing lets interrupts be delivered to a vCPU running in VMX non-root operation without
+//! a VM exit: the sender records the vector in the posted-interrupt descriptor of the target, and notifies the
+//! physical CPU running it with the notification vector.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Outstanding Notification (bit 256 of the descriptor).
+const PI_CTRL_ON: u64 = 1 << 0;
+/// Suppress Notification (bit 257 of the descriptor).
+const PI_CTRL_SN: u64 = 1 << 1;
+/// Notification Vector (bits 279:272 of the descriptor).
+const PI_CTRL_NV_SHIFT: u64 = 16;
+const PI_CTRL_NV_MASK: u64 = 0xff << PI_CTRL_NV_SHIFT;
+/// Notification Destination (bits 319:288 of the descriptor).
+const PI_CTRL_NDST_SHIFT: u64 = 32;
+const PI_CTRL_NDST_MASK: u64 = 0xffff_ffff << PI_CTRL_NDST_SHIFT;
+
+/// Table 30-1. Format of Posted-Interrupt Descriptor
+///
+/// The 64-byte descriptor is updated atomically by both the senders and the processor, as the SDM requires.
+#[repr(C, align(64))]
+#[derive(Default)]
+pub struct PostedInterruptDesc {
+    /// Posted-interrupt requests (PIR), one bit for each interrupt vector.
+    pir: [AtomicU64; 4],
+    /// Outstanding Notification (ON), Suppress Notification (SN), Notification Vector (NV) and Notification
+    /// Destination (NDST).
+    control: AtomicU64,
+    _reserved: [u64; 3],
+}
+
+impl PostedInterruptDesc {
+    /// Set the notification vector and the notification destination, i.e. the physical APIC ID of the CPU running
+    /// the vCPU (in bits 15:8 if that CPU is in xAPIC mode).
+    pub fn set_notification(&self, nv: u8, ndst: u32) {
+        let _ = self
+            .control
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |ctrl| {
+                Some(
+                    (ctrl & !(PI_CTRL_NV_MASK | PI_CTRL_NDST_MASK))
+                        | ((nv as u64) << PI_CTRL_NV_SHIFT)
+                        | ((ndst as u64) << PI_CTRL_NDST_SHIFT),
+                )
+            });
+    }
+
+    /// Update the notification destination, keeping the notification vector.
+    pub fn set_ndst(&self, ndst: u32) {
+        self.set_notification(self.nv(), ndst);
+    }
+
+    /// Set or clear the Suppress Notification bit.
+    pub fn set_sn(&self, suppress: bool) {
+        if suppress {
+            self.control.fetch_or(PI_CTRL_SN, Ordering::AcqRel);
+        } else {
+            self.control.fetch_and(!PI_CTRL_SN, Ordering::AcqRel);
+        }
+    }
+
+    /// The notification vector.
+    pub fn nv(&self) -> u8 {
+        ((self.control.load(Ordering::Acquire) & PI_CTRL_NV_MASK) >> PI_CTRL_NV_SHIFT) as u8
+    }
+
+    /// The notification destination.
+    pub fn ndst(&self) -> u32 {
+        ((self.control.load(Ordering::Acquire) & PI_CTRL_NDST_MASK) >> PI_CTRL_NDST_SHIFT) as u32
+    }
+
+    /// Post `vector` in the PIR.
+    ///
+    /// Returns whether the notification vector should be sent, that is, if the notification is not suppressed and
+    /// none is outstanding already. ON is set in that case.
+    pub fn post(&self, vector: u8) -> bool {
+        self.pir[vector as usize >> 6].fetch_or(1 << (vector & 0x3f), Ordering::AcqRel);
+
+        self.control
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |ctrl| {
+                (ctrl & (PI_CTRL_ON | PI_CTRL_SN) == 0).then_some(ctrl | PI_CTRL_ON)
+            })
+            .is_ok()
+    }
+
+    /// Clear ON, and take all the requests out of the PIR.
+    pub fn take_pir(&self) -> [u64; 4] {
+        self.control.fetch_and(!PI_CTRL_ON, Ordering::AcqRel);
+        core::array::from_fn(|i| self.pir[i].swap(0, Ordering::AcqRel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PostedInterruptDesc;
+
+    #[test]
+    fn test_posted_interrupt_desc() {
+        assert_eq!(core::mem::size_of::<PostedInterruptDesc>(), 64);
+        assert_eq!(core::mem::align_of::<PostedInterruptDesc>(), 64);
+
+        let desc = PostedInterruptDesc::default();
+        desc.set_notification(0xf2, 0x0300);
+        assert_eq!(desc.nv(), 0xf2);
+        assert_eq!(desc.ndst(), 0x0300);
+
+        desc.set_ndst(0x0500);
+        assert_eq!(desc.nv(), 0xf2);
+        assert_eq!(desc.ndst(), 0x0500);
+
+        // The first request notifies, the following ones wait for the outstanding notification.
+        assert!(desc.post(0x30));
+        assert!(!desc.post(0x81));
+        assert_eq!(desc.take_pir(), [1 << 48, 0, 1 << 1, 0]);
+        assert_eq!(desc.take_pir(), [0; 4]);
+
+        // ON has been cleared, but notifications are suppressed.
+        desc.set_sn(true);
+        assert!(!desc.post(0xff));
+        desc.set_sn(false);
+        assert!(desc.post(0x20));
+        assert_eq!(desc.take_pir(), [1 << 32, 0, 0, 1 << 63]);
+    }
+}
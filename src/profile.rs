@@ -0,0 +1,152 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! 11.4.8 Local APIC Version Register
+//! The model of local APIC presented to the guest, as reported by the Local APIC Version Register.
+
+/// Max LVT Entry (bits 23:16): the number of LVT entries minus 1.
+const VERSION_MAX_LVT_SHIFT: u32 = 16;
+/// Support for EOI-broadcast suppression (bit 24).
+const VERSION_DIRECTED_EOI: u32 = 1 << 24;
+/// AMD: the extended APIC register space at 400H is present (bit 31).
+const VERSION_EXT_APIC_SPACE: u32 = 1 << 31;
+
+/// The number of extended LVT registers that fit in the extended register space, at 500H–530H.
+pub(crate) const MAX_EXT_LVT_COUNT: u8 = 4;
+
+/// The AMD extended APIC registers implemented by the local APIC, as reported by the Extended APIC Feature
+/// register (400H).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AmdExtApic {
+    /// Whether the Interrupt Enable registers (480H–4F0H) are implemented, which hold interrupts of disabled
+    /// vectors in the IRR while enabled by the Extended APIC Control register.
+    pub ier: bool,
+    /// Whether the Specific End Of Interrupt register (420H) is implemented, which ends the interrupt with the
+    /// written vector rather than the highest priority one in service.
+    pub seoi: bool,
+    /// The number of extended LVT registers, at most 4.
+    pub ext_lvt_count: u8,
+}
+
+impl Default for AmdExtApic {
+    /// IER and SEOI, with the 4 extended LVT registers of recent AMD processors.
+    fn default() -> Self {
+        Self {
+            ier: true,
+            seoi: true,
+            ext_lvt_count: MAX_EXT_LVT_COUNT,
+        }
+    }
+}
+
+impl AmdExtApic {
+    /// The value of the Extended APIC Feature register.
+    pub const fn feature_reg(&self) -> u32 {
+        let count = if self.ext_lvt_count < MAX_EXT_LVT_COUNT {
+            self.ext_lvt_count
+        } else {
+            MAX_EXT_LVT_COUNT
+        };
+        (count as u32) << 16 | (self.seoi as u32) << 1 | self.ier as u32
+    }
+}
+
+/// The features of the local APIC model presented to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LapicProfile {
+    /// Version (bits 7:0 of the Version register): 1XH for an integrated local APIC, e.g. 14H or 15H.
+    pub version: u8,
+    /// Whether the LVT CMCI register (FEE0 02F0H) is implemented. The Max LVT Entry is 6 with it, and 5 without,
+    /// in which case the register is reserved.
+    pub cmci: bool,
+    /// Whether EOI-broadcast suppression, a.k.a. directed EOI, is supported (SDM Vol. 3A, Section 11.8.5). Without
+    /// it, the Suppress EOI Broadcasts bit of the SVR is reserved.
+    pub directed_eoi: bool,
+    /// Whether the remote read IPI (delivery mode 011 of the ICR) is emulated, as on Pentium and P6 family
+    /// processors. Without it, the delivery mode is reserved as on later processors, and the IPI is not sent.
+    pub remote_read: bool,
+    /// The AMD extended APIC register space, if implemented.
+    pub amd_ext: Option<AmdExtApic>,
+}
+
+impl Default for LapicProfile {
+    /// Version 14H, with the LVT CMCI register, without EOI-broadcast suppression and remote read.
+    fn default() -> Self {
+        Self {
+            version: 0x14,
+            cmci: true,
+            directed_eoi: false,
+            remote_read: false,
+            amd_ext: None,
+        }
+    }
+}
+
+impl LapicProfile {
+    /// The local APIC of AMD processors: version 10H, without the LVT CMCI register and EOI-broadcast suppression,
+    /// and with the extended register space described by `amd_ext`.
+    pub const fn amd(amd_ext: AmdExtApic) -> Self {
+        Self {
+            version: 0x10,
+            cmci: false,
+            directed_eoi: false,
+            remote_read: false,
+            amd_ext: Some(amd_ext),
+        }
+    }
+
+    /// Figure 11-7. Local APIC Version Register
+    /// The value of the Version register for this profile.
+    pub const fn version_reg(&self) -> u32 {
+        let max_lvt = if self.cmci { 6 } else { 5 };
+        let mut reg = self.version as u32 | (max_lvt << VERSION_MAX_LVT_SHIFT);
+        if self.directed_eoi {
+            reg |= VERSION_DIRECTED_EOI;
+        }
+        if self.amd_ext.is_some() {
+            reg |= VERSION_EXT_APIC_SPACE;
+        }
+        reg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AmdExtApic, LapicProfile};
+
+    #[test]
+    fn test_version_reg() {
+        assert_eq!(LapicProfile::default().version_reg(), 0x0006_0014);
+
+        let profile = LapicProfile {
+            version: 0x15,
+            cmci: false,
+            directed_eoi: true,
+            remote_read: false,
+            amd_ext: None,
+        };
+        assert_eq!(profile.version_reg(), 0x0105_0015);
+
+        let profile = LapicProfile::amd(AmdExtApic::default());
+        assert_eq!(profile.version_reg(), 0x8005_0010);
+        assert_eq!(profile.amd_ext.unwrap().feature_reg(), 0x0004_0003);
+
+        let amd_ext = AmdExtApic {
+            ier: false,
+            seoi: true,
+            ext_lvt_count: 8,
+        };
+        assert_eq!(amd_ext.feature_reg(), 0x0004_0002);
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! KVM paravirtual EOI (Documentation/virt/kvm/x86/msr.rst, MSR_KVM_PV_EOI_EN)
+//! The guest registers a flag in its memory, which the host sets while the interrupt in service needs no EOI exit.
+//! The guest then clears the flag instead of writing the EOI register, and the host performs the EOI on its behalf
+//! the next time it handles the local APIC.
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use axaddrspace::HostVirtAddr;
+
+/// MSR_KVM_PV_EOI_EN, holding the guest physical address of the flag, 4-byte aligned, and the enable bit.
+pub const MSR_KVM_PV_EOI_EN: u32 = 0x4b56_4d04;
+
+/// KVM_MSR_ENABLED (bit 0 of the MSR).
+const PV_EOI_MSR_ENABLED: u64 = 1 << 0;
+/// The address of the flag, in bits 63:2 of the MSR.
+pub(crate) const PV_EOI_MSR_ADDR_MASK: u64 = !0b11;
+/// KVM_PV_EOI_ENABLED (bit 0 of the flag): the guest may skip the EOI write.
+const PV_EOI_FLAG: u8 = 1 << 0;
+
+/// The paravirtual EOI state of a local APIC.
+#[derive(Default)]
+pub(crate) struct PvEoi {
+    /// The value of MSR_KVM_PV_EOI_EN.
+    msr: u64,
+    /// The flag in guest memory, if enabled.
+    flag: Option<NonNull<u8>>,
+    /// Whether the host set the flag before the last VM entry.
+    pending: bool,
+}
+
+impl PvEoi {
+    /// The value of MSR_KVM_PV_EOI_EN.
+    pub(crate) const fn msr(&self) -> u64 {
+        self.msr
+    }
+
+    /// Returns whether `msr` enables paravirtual EOI.
+    pub(crate) const fn is_enabled_by(msr: u64) -> bool {
+        msr & PV_EOI_MSR_ENABLED != 0
+    }
+
+    /// Set the MSR to `msr`, with the flag mapped at `flag` in the host if enabled.
+    pub(crate) fn set_msr(&mut self, msr: u64, flag: Option<HostVirtAddr>) {
+        self.msr = msr;
+        self.flag = flag.and_then(|flag| NonNull::new(flag.as_mut_ptr()));
+        self.pending = false;
+    }
+
+    /// Returns whether paravirtual EOI is enabled.
+    pub(crate) const fn is_enabled(&self) -> bool {
+        self.flag.is_some()
+    }
+
+    /// The flag in guest memory, if enabled.
+    fn flag(&self) -> Option<&AtomicU8> {
+        // SAFETY: the flag was translated when the MSR was written, and guest memory stays mapped while the VM runs.
+        // Other vCPUs of the guest may access the other bits of the byte at any time, so it's only accessed with
+        // atomic operations, as the guest does.
+        self.flag
+            .map(|flag| unsafe { AtomicU8::from_ptr(flag.as_ptr()) })
+    }
+
+    /// Set the flag in guest memory before a VM entry, letting the guest skip the EOI of the interrupt in service.
+    pub(crate) fn set_pending(&mut self) {
+        if let Some(flag) = self.flag() {
+            flag.fetch_or(PV_EOI_FLAG, Ordering::SeqCst);
+            self.pending = true;
+        }
+    }
+
+    /// Take the flag back after the guest ran. Returns whether the guest cleared it, i.e. performed an EOI without
+    /// writing the EOI register.
+    ///
+    /// The flag is cleared in the same atomic operation as it's read, so that the guest either ended the interrupt
+    /// before, or must write the EOI register after.
+    pub(crate) fn take_pending(&mut self) -> bool {
+        if !core::mem::take(&mut self.pending) {
+            return false;
+        }
+        self.flag()
+            .is_some_and(|flag| flag.fetch_and(!PV_EOI_FLAG, Ordering::SeqCst) & PV_EOI_FLAG == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pv_eoi_flag() {
+        let mut guest_flag = 0u8;
+        let flag = &raw mut guest_flag;
+        let mut pv_eoi = PvEoi::default();
+        assert!(!pv_eoi.is_enabled());
+
+        let msr = 0x1234_5000 | PV_EOI_MSR_ENABLED;
+        assert!(PvEoi::is_enabled_by(msr));
+        pv_eoi.set_msr(msr, Some(HostVirtAddr::from_usize(flag as usize)));
+        assert!(pv_eoi.is_enabled());
+        assert_eq!(pv_eoi.msr(), msr);
+
+        // Not set by the host
+        assert!(!pv_eoi.take_pending());
+
+        // The guest skipped the EOI write, clearing the flag
+        pv_eoi.set_pending();
+        assert_eq!(unsafe { flag.read() }, PV_EOI_FLAG);
+        unsafe { flag.write(0) };
+        assert!(pv_eoi.take_pending());
+        assert!(!pv_eoi.take_pending());
+
+        // The guest did not end the interrupt: the flag is cleared by the host
+        pv_eoi.set_pending();
+        assert!(!pv_eoi.take_pending());
+        assert_eq!(unsafe { flag.read() }, 0);
+
+        pv_eoi.set_msr(0, None);
+        pv_eoi.set_pending();
+        assert!(!pv_eoi.take_pending());
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! AMD64 APM Vol. 2, Chapter 16, Extended APIC Registers
+//! The extended register space at 400H–53FH, present when bit 31 of the Version register is set.
+
+use tock_registers::LocalRegisterCopy;
+use tock_registers::register_bitfields;
+use tock_registers::registers::{ReadWrite, WriteOnly};
+
+register_bitfields! {
+    u32,
+    pub EXTENDED_APIC_FEATURE [
+        /// Extended LVT Count: the number of extended LVT registers, starting at 500H.
+        ExtLvtCount OFFSET(16) NUMBITS(8) [],
+        /// Extended APIC ID Capability: 8-bit APIC IDs are supported.
+        ExtApicIdCap OFFSET(2) NUMBITS(1) [],
+        /// Specific End Of Interrupt Capable: the SEOI register is implemented.
+        SpecificEoiCap OFFSET(1) NUMBITS(1) [],
+        /// Interrupt Enable Register Capable: the IER registers are implemented.
+        IntrEnRegCap OFFSET(0) NUMBITS(1) [],
+    ]
+}
+
+register_bitfields! {
+    u32,
+    pub EXTENDED_APIC_CONTROL [
+        /// Extended APIC ID Enable.
+        ExtApicIdEn OFFSET(2) NUMBITS(1) [],
+        /// SEOI Enable: writes to the SEOI register end the interrupt with the written vector.
+        SeoiEn OFFSET(1) NUMBITS(1) [],
+        /// Interrupt Enable Register Enable: interrupts whose IER bit is clear are held in the IRR.
+        IerEn OFFSET(0) NUMBITS(1) [],
+    ]
+}
+
+register_bitfields! {
+    u32,
+    pub SPECIFIC_EOI [
+        /// End Of Interrupt Vector.
+        EoiVec OFFSET(0) NUMBITS(8) [],
+    ]
+}
+
+register_bitfields! {
+    u32,
+    pub EXTENDED_LVT [
+        /// Mask.
+        Mask OFFSET(16) NUMBITS(1) [
+            NotMasked = 0,
+            Masked = 1
+        ],
+        /// Delivery Status, read-only.
+        DeliveryStatus OFFSET(12) NUMBITS(1) [
+            Idle = 0,
+            SendPending = 1
+        ],
+        /// Message Type.
+        MessageType OFFSET(8) NUMBITS(3) [
+            Fixed = 0b000,
+            SMI = 0b010,
+            NMI = 0b100,
+            ExtINT = 0b111
+        ],
+        /// Vector.
+        Vector OFFSET(0) NUMBITS(8) [],
+    ]
+}
+
+/// Extended APIC Feature Register (EXTFEAT) using MMIO.
+/// - Address: FEE0 0400H
+/// - Read-only, describing the extended registers implemented.
+pub type ExtendedApicFeatureRegisterMmio = ReadWrite<u32, EXTENDED_APIC_FEATURE::Register>;
+
+/// Extended APIC Control Register (EXTCTRL) using MMIO.
+/// - Address: FEE0 0410H
+/// - Value after reset: 0
+pub type ExtendedApicControlRegisterMmio = ReadWrite<u32, EXTENDED_APIC_CONTROL::Register>;
+
+/// A read-write copy of Extended APIC Control Register (FEE0 0410H).
+#[allow(dead_code)]
+pub type ExtendedApicControlRegisterLocal = LocalRegisterCopy<u32, EXTENDED_APIC_CONTROL::Register>;
+
+/// Specific End Of Interrupt Register (SEOI) using MMIO.
+/// - Address: FEE0 0420H
+/// - Write-only
+pub type SpecificEoiRegisterMmio = WriteOnly<u32, SPECIFIC_EOI::Register>;
+
+/// Extended LVT Register using MMIO, for the IBS, MCA thresholding and other model-specific interrupt sources.
+/// - Address: FEE0 0500H + 10H * n
+/// - Value after reset: 0001 0000H
+///
+/// The extended LVT registers are accessed as the `EXT_LVT` array of [`LocalAPICRegs`](super::LocalAPICRegs).
+#[allow(dead_code)]
+pub type ExtendedLvtRegisterMmio = ReadWrite<u32, EXTENDED_LVT::Register>;
@@ -45,8 +45,20 @@ register_bitfields! {
             /// All Excluding Self
             AllExcludingSelf = 0b11
         ],
-        /// Reserved
-        Reserved1 OFFSET(16) NUMBITS(2) [],
+        /// Remote Read Status (Read Only)
+        /// (Only in Pentium and P6 family processors, reserved in later ones.)
+        /// Indicates the status of the last remote read, whose result is in the Remote Read Register (RRD):
+        /// - 00: (Invalid) The remote read failed, or no remote read was sent.
+        /// - 01: (Remote read in progress)
+        /// - 10: (Valid) The remote read completed, and RRD holds the register of the destination.
+        RemoteReadStatus OFFSET(16) NUMBITS(2) [
+            /// Invalid
+            Invalid = 0b00,
+            /// In Progress
+            InProgress = 0b01,
+            /// Valid
+            Valid = 0b10
+        ],
         /// Trigger Mode
         /// Selects the trigger mode when using the INIT level de-assert delivery mode:
         ///     edge (0) or level (1).
@@ -94,7 +106,10 @@ register_bitfields! {
         ///     Same as fixed mode, except that the interrupt is delivered to the processor executing at the lowest priority among the set of processors specified in the destination field. The ability for a processor to send a lowest priority IPI is model specific and should be avoided by BIOS and operating system software.
         /// - 010 (SMI)
         ///     Delivers an SMI interrupt to the target processor or processors. The vector field must be programmed to 00H for future compatibility.
-        /// - 011 (Reserved)
+        /// - 011 (Remote Read)
+        ///     (Only in Pentium and P6 family processors, reserved in later ones.)
+        ///     Reads the register of the destination local APIC at the address given in the vector field (offset >> 4)
+        ///     into the Remote Read Register of the sender. The destination must be a single local APIC.
         /// - 100 (NMI)
         ///     Delivers an NMI interrupt to the target processor or processors. The vector information is ignored.
         /// - 101 (INIT)
@@ -121,8 +136,8 @@ register_bitfields! {
             LowestPriority = 0b001,
             /// SMI
             SMI = 0b010,
-            /// Reserved
-            Reserved011 = 0b011,
+            /// Remote Read (Pentium and P6 family processors only, reserved in later ones)
+            RemoteRead = 0b011,
             /// NMI
             NMI = 0b100,
             /// INIT
@@ -18,13 +18,17 @@ pub mod timer;
 mod apic_base;
 mod dfr;
 mod esr;
+mod ext;
 mod icr;
+mod msi;
 mod svr;
 
 pub use apic_base::*;
 pub use dfr::*;
 pub use esr::*;
+pub use ext::*;
 pub use icr::*;
+pub use msi::*;
 pub use svr::*;
 
 use tock_registers::register_structs;
@@ -44,7 +48,7 @@ register_structs! {
         (0x20 => pub ID: ReadWrite<u32>),
         (0x24 => _reserved1),
         /// Local APIC Version register (VVER): the 32-bit field located at offset 030H on the virtual-APIC page.
-        (0x30 => pub VERSION: ReadOnly<u32>),
+        (0x30 => pub VERSION: ReadWrite<u32>),
         (0x34 => _reserved2),
         /// Virtual task-priority register (VTPR): the 32-bit field located at offset 080H on the virtual-APIC page.
         (0x80 => pub TPR: ReadWrite<u32>),
@@ -59,7 +63,7 @@ register_structs! {
         (0xB0 => pub EOI: WriteOnly<u32>),
         (0xB4 => _reserved6),
         /// Virtual Remote Read Register (RRD): the 32-bit field located at offset 0C0H on the virtual-APIC page.
-        (0xC0 => pub RRD: ReadOnly<u32>),
+        (0xC0 => pub RRD: ReadWrite<u32>),
         (0xC4 => _reserved7),
         /// Virtual Logical Destination Register (LDR): the 32-bit field located at offset 0D0H on the virtual-APIC page.
         (0xD0 => pub LDR: ReadWrite<u32>),
@@ -77,13 +81,13 @@ register_structs! {
         /// Virtual trigger-mode register (VTMR):
         /// the 256-bit value comprising eight non-contiguous 32-bit fields at offsets
         /// 180H, 190H, 1A0H, 1B0H, 1C0H, 1D0H, 1E0H, and 1F0H on the virtual-APIC page.
-        (0x180 => pub TMR: [ReadOnly<u128>; 8]),
+        (0x180 => pub TMR: [ReadWrite<u128>; 8]),
         /// Virtual interrupt-request register (VIRR):
         /// the 256-bit value comprising eight non-contiguous 32-bit fields at offsets
         /// 200H, 210H, 220H, 230H, 240H, 250H, 260H, and 270H on the virtual-APIC page.
         /// Bit x of the VIRR is at bit position (x & 1FH) at offset (200H | ((x & E0H) » 1)).
         /// The processor uses only the low 4 bytes of each of the 16-Byte fields at offsets 200H, 210H, 220H, 230H, 240H, 250H, 260H, and 270H.
-        (0x200 => pub IRR: [ReadOnly<u128>; 8]),
+        (0x200 => pub IRR: [ReadWrite<u128>; 8]),
         /// Virtual error-status register (VESR): the 32-bit field located at offset 280H on the virtual-APIC page.
         (0x280 => pub ESR: ErrorStatusRegisterMmio),
         (0x284 => _reserved11),
@@ -126,6 +130,22 @@ register_structs! {
         /// Available only in x2APIC mode.
         (0x3F0 => pub SELF_IPI: WriteOnly<u32>),
         (0x3F4 => _reserved24),
+        /// Extended APIC Feature Register (EXTFEAT), AMD only.
+        (0x400 => pub EXT_FEATURE: ExtendedApicFeatureRegisterMmio),
+        (0x404 => _reserved25),
+        /// Extended APIC Control Register (EXTCTRL), AMD only.
+        (0x410 => pub EXT_CONTROL: ExtendedApicControlRegisterMmio),
+        (0x414 => _reserved26),
+        /// Specific End Of Interrupt Register (SEOI), AMD only.
+        (0x420 => pub SEOI: SpecificEoiRegisterMmio),
+        (0x424 => _reserved27),
+        /// Interrupt Enable Registers (IER), AMD only:
+        /// the 256-bit value comprising eight non-contiguous 32-bit fields at offsets
+        /// 480H, 490H, 4A0H, 4B0H, 4C0H, 4D0H, 4E0H, and 4F0H, laid out as the IRR.
+        (0x480 => pub IER: [ReadWrite<u128>; 8]),
+        /// Extended LVT Registers, AMD only: the 32-bit fields at offsets 500H, 510H, 520H and 530H.
+        (0x500 => pub EXT_LVT: [ReadWrite<u128>; 4]),
+        (0x540 => _reserved28),
         (0x1000 => @END),
     }
 }
@@ -0,0 +1,138 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! 11.11 Message Signalled Interrupts
+//! Message signalled interrupts (MSI and MSI-X) are delivered to the local APICs by a memory write of the message
+//! data register value to the address specified by the message address register (see Figure 11-24 and Figure 11-25).
+
+use tock_registers::LocalRegisterCopy;
+use tock_registers::register_bitfields;
+
+register_bitfields! {
+    u32,
+    pub MSI_ADDRESS [
+        /// Bits 31-20 — These bits contain a fixed value for interrupt messages (0FEEH).
+        /// This value locates interrupts at the 1-MByte area with a base address of 4G – 18M.
+        FixedAddress OFFSET(20) NUMBITS(12) [
+            /// 0FEEH
+            Interrupt = 0xFEE
+        ],
+        /// Destination ID — This field contains an 8-bit destination ID.
+        /// It identifies the message’s target processor(s).
+        /// The destination ID corresponds to bits 63:56 of the I/O APIC Redirection Table Entry
+        /// if the IOAPIC is used to dispatch the interrupt to the processor.
+        DestinationID OFFSET(12) NUMBITS(8) [],
+        /// Extended Destination ID — Bits 11-5 are reserved in the format of Figure 11-24.
+        /// Hypervisors supporting more than 255 x2APIC IDs without interrupt remapping use them as bits 14-8 of
+        /// the destination ID.
+        ExtendedDestinationID OFFSET(5) NUMBITS(7) [],
+        /// Interrupt Format — Set for the remappable format of interrupt remapping, which is not supported.
+        InterruptFormat OFFSET(4) NUMBITS(1) [],
+        /// Redirection hint indication (RH) — This bit indicates whether the message should be directed to the
+        /// processor with the lowest interrupt priority among processors that can receive the interrupt.
+        /// - When RH is 0, the interrupt is directed to the processor listed in the Destination ID field.
+        /// - When RH is 1 and the physical destination mode is used, the Destination ID field must not be set to FFH;
+        ///   it must point to a processor that is present and enabled to receive the interrupt.
+        /// - When RH is 1 and the logical destination mode is active in a system using a flat addressing model,
+        ///   the Destination ID field must be set so that bits set to 1 identify processors that are present and
+        ///   enabled to receive the interrupt.
+        RedirectionHint OFFSET(3) NUMBITS(1) [],
+        /// Destination mode (DM) — This bit indicates whether the Destination ID field should be interpreted as
+        /// logical or physical APIC ID for delivery of the lowest priority interrupt.
+        /// If RH is 1 and DM is 0, the Destination ID field is in physical destination mode and only the processor
+        /// in the system that has the matching APIC ID is considered for delivery of that interrupt
+        /// (this means no re-direction).
+        /// If RH is 1 and DM is 1, the Destination ID Field is interpreted as in logical destination mode and the
+        /// redirection is limited to only those processors that are part of the logical group of processors based
+        /// on the processor’s logical APIC ID and the Destination ID field in the message.
+        /// The logical group of processors consists of those identified by matching the 8-bit Destination ID with
+        /// the logical destination identified by the Destination Format Register and the Logical Destination
+        /// Register in each local APIC.
+        /// If RH is 0, then the DM bit is ignored and the message is sent ahead independent of whether the physical
+        /// or logical destination mode is used.
+        DestinationMode OFFSET(2) NUMBITS(1) [
+            /// Physical
+            Physical = 0,
+            /// Logical
+            Logical = 1
+        ],
+        /// Reserved
+        Reserved OFFSET(0) NUMBITS(2) []
+    ]
+}
+
+register_bitfields! {
+    u32,
+    pub MSI_DATA [
+        /// Reserved
+        Reserved1 OFFSET(16) NUMBITS(16) [],
+        /// Trigger Mode — This field indicates the signal type that will trigger a message.
+        TriggerMode OFFSET(15) NUMBITS(1) [
+            /// Edge
+            Edge = 0,
+            /// Level
+            Level = 1
+        ],
+        /// Level — Edge triggered interrupt messages are always interpreted as assert messages.
+        /// For level triggered interrupts, this bit reflects the state of the interrupt input.
+        Level OFFSET(14) NUMBITS(1) [
+            /// De-assert
+            DeAssert = 0,
+            /// Assert
+            Assert = 1
+        ],
+        /// Reserved
+        Reserved0 OFFSET(11) NUMBITS(3) [],
+        /// Delivery Mode — This field is identical to the corresponding field in the I/O APIC redirection table.
+        /// - 000 (Fixed Mode) Deliver the signal to all the agents listed in the destination.
+        /// - 001 (Lowest Priority) Deliver the signal to the agent that is executing at the lowest priority of
+        ///   all the agents listed in the destination field.
+        /// - 010 (System Management Interrupt or SMI) The delivery mode is edge only.
+        /// - 100 (NMI) Deliver the signal to all the agents listed in the destination field.
+        ///   The vector information is ignored. NMI is an edge triggered interrupt regardless of the Trigger Mode.
+        /// - 101 (INIT) Deliver this signal to all the agents listed in the destination field.
+        ///   The vector information is ignored. INIT is an edge triggered interrupt regardless of the Trigger Mode.
+        /// - 111 (ExtINT) Deliver the signal to the INTR signal of all agents in the destination field
+        ///   (as an interrupt that originated from an 8259A compatible interrupt controller).
+        ///   The vector is supplied by the INTA cycle issued by the activation of the ExtINT.
+        ///   ExtINT is an edge triggered interrupt.
+        DeliveryMode OFFSET(8) NUMBITS(3) [
+            /// Fixed
+            Fixed = 0b000,
+            /// Lowest Priority
+            LowestPriority = 0b001,
+            /// SMI
+            SMI = 0b010,
+            /// Reserved
+            Reserved011 = 0b011,
+            /// NMI
+            NMI = 0b100,
+            /// INIT
+            INIT = 0b101,
+            /// Reserved
+            Reserved110 = 0b110,
+            /// ExtINT
+            ExtINT = 0b111
+        ],
+        /// Vector — This 8-bit field contains the interrupt vector associated with the message.
+        /// Values range from 010H to 0FEH. Vectors 00H to 0FH are reserved.
+        Vector OFFSET(0) NUMBITS(8) []
+    ]
+}
+
+/// A copy of the low doubleword of the Message Address Register, see Figure 11-24.
+/// - Address: 0FEEX XXXXH
+pub type MsiAddressRegisterLocal = LocalRegisterCopy<u32, MSI_ADDRESS::Register>;
+
+/// A copy of the Message Data Register, see Figure 11-25.
+pub type MsiDataRegisterLocal = LocalRegisterCopy<u32, MSI_DATA::Register>;
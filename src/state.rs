@@ -0,0 +1,94 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshot of the local APIC state, used to save a vCPU and restore it later or on another host.
+
+/// The version of [`LapicState`] produced by this crate. Bumped whenever the layout or the meaning of a field
+/// changes, so that a state saved by another version is rejected on restore instead of being misread.
+pub const LAPIC_STATE_VERSION: u32 = 2;
+
+/// The number of 16-byte register slots in the first 1 KiB of the local APIC register page, i.e. offsets
+/// 000H–3F0H (SDM Vol. 3A, Section 11.4.1, Table 11-1).
+pub const LAPIC_REG_SLOTS: usize = 0x400 / 0x10;
+
+/// The number of 16-byte register slots in the AMD extended APIC register space, i.e. offsets 400H–530H.
+pub const LAPIC_EXT_REG_SLOTS: usize = 0x140 / 0x10;
+
+/// The state of the APIC timer, with the pending deadline stored relative to the time of the save.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TimerState {
+    /// LVT Timer Register.
+    pub lvt: u32,
+    /// Initial Count Register.
+    pub initial_count: u32,
+    /// Divide Configuration Register.
+    pub divide_config: u32,
+    /// Nanoseconds left until the timer expires, or `None` if the timer is stopped. A deadline already reached
+    /// but not handled yet is saved as 0, and the timer fires as soon as it is restored.
+    pub remaining_ns: Option<u64>,
+}
+
+/// The complete architectural and emulation state of a local APIC.
+///
+/// The state is plain data: the VMM picks the encoding used to store or transfer it, and checks
+/// [`Self::version`] against [`LAPIC_STATE_VERSION`] on the way back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LapicState {
+    /// [`LAPIC_STATE_VERSION`] at the time of the save.
+    pub version: u32,
+    /// IA32_APIC_BASE MSR.
+    pub apic_base: u64,
+    /// The low 32 bits of each register slot of the virtual-APIC page, indexed by the register offset divided by
+    /// 16. The 256-bit ISR, TMR and IRR are spread across 8 slots each, as on the page.
+    pub regs: [u32; LAPIC_REG_SLOTS],
+    /// The low 32 bits of each register slot of the AMD extended APIC register space, indexed by the register
+    /// offset minus 400H divided by 16. All 0 if the local APIC does not implement it.
+    pub ext_regs: [u32; LAPIC_EXT_REG_SLOTS],
+    /// The last accepted value of the SVR.
+    pub svr: u32,
+    /// The last accepted value of the LVT registers, in the order CMCI, Timer, Thermal Monitor, Performance
+    /// Counter, LINT0, LINT1 and Error.
+    pub lvt: [u32; 7],
+    /// Errors detected since the last write to the ESR, made visible by the next write.
+    pub esr_pending: u32,
+    /// The vector of the highest priority interrupt in service.
+    pub isrv: u32,
+    /// Whether LINT0 and LINT1 are asserted, with the pin polarity applied.
+    pub lint_asserted: [bool; 2],
+    /// Vectors routed to the local APIC as level-triggered interrupts.
+    pub level_vectors: [u64; 4],
+    /// The APIC timer.
+    pub timer: TimerState,
+    /// MSR_KVM_PV_EOI_EN. The paravirtual EOI flag in guest memory is clear when the state is saved.
+    pub pv_eoi_msr: u64,
+}
+
+impl Default for LapicState {
+    fn default() -> Self {
+        Self {
+            version: LAPIC_STATE_VERSION,
+            apic_base: 0,
+            regs: [0; LAPIC_REG_SLOTS],
+            ext_regs: [0; LAPIC_EXT_REG_SLOTS],
+            svr: 0,
+            lvt: [0; 7],
+            esr_pending: 0,
+            isrv: 0,
+            lint_asserted: [false; 2],
+            level_vectors: [0; 4],
+            timer: TimerState::default(),
+            pv_eoi_msr: 0,
+        }
+    }
+}
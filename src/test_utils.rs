@@ -0,0 +1,194 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Implementations of the `axvisor_api` interfaces for the unit tests.
+//!
+//! Each test runs on its own thread, so the fake clock, the host timers and the injected interrupts are
+//! thread-local: tests do not see each other's. One tick is one nanosecond, and host timers only fire when a test
+//! advances the clock with [`advance_ticks`].
+
+extern crate std;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::cell::{Cell, RefCell};
+use std::alloc::{Layout, alloc_zeroed, dealloc};
+
+use axvisor_api::{
+    memory::{MemoryIf, PhysAddr, VirtAddr},
+    time::{CancelToken, Nanos, Ticks, TimeIf, TimeValue},
+    vmm::{InterruptVector, VCpuId, VCpuSet, VMId, VmmIf},
+};
+use memory_addr::PAGE_SIZE_4K;
+
+type TimerCallback = Box<dyn FnOnce(TimeValue) + Send + 'static>;
+
+std::thread_local! {
+    static TICKS: Cell<Ticks> = const { Cell::new(1) };
+    static TIMERS: RefCell<Vec<(CancelToken, TimeValue, TimerCallback)>> = const { RefCell::new(Vec::new()) };
+    static NEXT_TOKEN: Cell<CancelToken> = const { Cell::new(0) };
+    static INJECTED: RefCell<Vec<(VMId, VCpuId, InterruptVector)>> = const { RefCell::new(Vec::new()) };
+    static KICKED: RefCell<Vec<(VMId, VCpuId)>> = const { RefCell::new(Vec::new()) };
+    static EOIS: RefCell<Vec<(VMId, u8)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Move the clock forward by `ticks`, firing the host timers whose deadline is reached.
+pub fn advance_ticks(ticks: Ticks) {
+    let now = TICKS.with(|t| {
+        t.set(t.get() + ticks);
+        t.get()
+    });
+    let due = TIMERS.with(|timers| {
+        let timers = &mut *timers.borrow_mut();
+        let (due, pending) = timers
+            .drain(..)
+            .partition::<Vec<_>, _>(|(_, deadline, _)| deadline.as_nanos() as u64 <= now);
+        *timers = pending;
+        due
+    });
+    for (_, _, callback) in due {
+        callback(TimeValue::from_nanos(now));
+    }
+}
+
+/// The number of host timers registered and not fired or cancelled yet.
+pub fn pending_timers() -> usize {
+    TIMERS.with(|timers| timers.borrow().len())
+}
+
+/// Take the interrupts injected through `axvisor_api::vmm` so far, as `(vm_id, vcpu_id, vector)`.
+pub fn take_injected() -> Vec<(VMId, VCpuId, InterruptVector)> {
+    INJECTED.with(|injected| injected.take())
+}
+
+/// A [`VCpuKicker`](crate::VCpuKicker) recording the kicked vCPUs.
+pub fn kick_vcpu(vm_id: VMId, vcpu_id: VCpuId) {
+    KICKED.with(|kicked| kicked.borrow_mut().push((vm_id, vcpu_id)));
+}
+
+/// Take the vCPUs kicked with [`kick_vcpu`] so far, as `(vm_id, vcpu_id)`.
+pub fn take_kicked() -> Vec<(VMId, VCpuId)> {
+    KICKED.with(|kicked| kicked.take())
+}
+
+/// An [`IoApicEoiHandler`](crate::IoApicEoiHandler) recording the broadcast EOIs.
+pub fn ioapic_eoi(vm_id: VMId, vector: u8) {
+    EOIS.with(|eois| eois.borrow_mut().push((vm_id, vector)));
+}
+
+/// Take the EOIs broadcast with [`ioapic_eoi`] so far, as `(vm_id, vector)`.
+pub fn take_ioapic_eois() -> Vec<(VMId, u8)> {
+    EOIS.with(|eois| eois.take())
+}
+
+struct TimeIfImpl;
+
+#[axvisor_api::api_impl]
+impl TimeIf for TimeIfImpl {
+    fn current_ticks() -> Ticks {
+        TICKS.with(Cell::get)
+    }
+
+    fn ticks_to_nanos(ticks: Ticks) -> Nanos {
+        ticks
+    }
+
+    fn nanos_to_ticks(nanos: Nanos) -> Ticks {
+        nanos
+    }
+
+    fn register_timer(deadline: TimeValue, callback: TimerCallback) -> CancelToken {
+        let token = NEXT_TOKEN.with(|t| {
+            t.set(t.get() + 1);
+            t.get()
+        });
+        TIMERS.with(|timers| timers.borrow_mut().push((token, deadline, callback)));
+        token
+    }
+
+    fn cancel_timer(token: CancelToken) {
+        TIMERS.with(|timers| timers.borrow_mut().retain(|(t, _, _)| *t != token));
+    }
+}
+
+struct VmmIfImpl;
+
+#[axvisor_api::api_impl]
+impl VmmIf for VmmIfImpl {
+    fn current_vm_id() -> VMId {
+        0
+    }
+
+    fn current_vcpu_id() -> VCpuId {
+        0
+    }
+
+    fn vcpu_num(_vm_id: VMId) -> Option<usize> {
+        None
+    }
+
+    fn active_vcpus(_vm_id: VMId) -> Option<usize> {
+        None
+    }
+
+    fn inject_interrupt(vm_id: VMId, vcpu_id: VCpuId, vector: InterruptVector) {
+        INJECTED.with(|injected| injected.borrow_mut().push((vm_id, vcpu_id, vector)));
+    }
+
+    fn inject_interrupt_to_cpus(vm_id: VMId, vcpu_set: VCpuSet, vector: InterruptVector) {
+        for vcpu_id in vcpu_set.into_iter() {
+            Self::inject_interrupt(vm_id, vcpu_id, vector);
+        }
+    }
+
+    fn notify_vcpu_timer_expired(_vm_id: VMId, _vcpu_id: VCpuId) {}
+}
+
+/// Host memory is identity-mapped: frames are allocated from the heap, and their virtual address is their physical
+/// address.
+struct MemoryIfImpl;
+
+const FRAME_LAYOUT: Layout = match Layout::from_size_align(PAGE_SIZE_4K, PAGE_SIZE_4K) {
+    Ok(layout) => layout,
+    Err(_) => panic!("invalid frame layout"),
+};
+
+#[axvisor_api::api_impl]
+impl MemoryIf for MemoryIfImpl {
+    fn alloc_frame() -> Option<PhysAddr> {
+        // SAFETY: the layout has a non-zero size.
+        let ptr = unsafe { alloc_zeroed(FRAME_LAYOUT) };
+        (!ptr.is_null()).then(|| PhysAddr::from_usize(ptr as usize))
+    }
+
+    fn alloc_contiguous_frames(_num_frames: usize, _frame_align_pow2: usize) -> Option<PhysAddr> {
+        unimplemented!("contiguous frames are not used by the local APIC");
+    }
+
+    fn dealloc_frame(addr: PhysAddr) {
+        // SAFETY: the frame was allocated by `alloc_frame` with the same layout.
+        unsafe { dealloc(addr.as_usize() as *mut u8, FRAME_LAYOUT) };
+    }
+
+    fn dealloc_contiguous_frames(_first_addr: PhysAddr, _num_frames: usize) {
+        unimplemented!("contiguous frames are not used by the local APIC");
+    }
+
+    fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+        VirtAddr::from_usize(addr.as_usize())
+    }
+
+    fn virt_to_phys(addr: VirtAddr) -> PhysAddr {
+        PhysAddr::from_usize(addr.as_usize())
+    }
+}
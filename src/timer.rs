@@ -25,8 +25,42 @@ use crate::{
         LVT_TIMER::{self, TimerMode::Value as TimerMode},
         LvtTimerRegisterLocal,
     },
+    state::TimerState,
 };
 
+/// The valid bits of the Divide Configuration Register.
+const DCR_MASK: u32 = 0b1011;
+
+/// Figure 11-10. Divide Configuration Register
+/// The timer counts down once every `1 << divide_shift(dcr)` ticks.
+pub(crate) const fn divide_shift(dcr: u32) -> u8 {
+    match dcr & DCR_MASK {
+        0b0000 => 1, // divide by 2
+        0b0001 => 2, // divide by 4
+        0b0010 => 3, // divide by 8
+        0b0011 => 4, // divide by 16
+        0b1000 => 5, // divide by 32
+        0b1001 => 6, // divide by 64
+        0b1010 => 7, // divide by 128
+        0b1011 => 0, // divide by 1
+        _ => panic!("internal error: invalid divide configuration register value after mask"),
+    }
+}
+
+/// How the APIC timer gets notified that its deadline is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimerBackend {
+    /// A host software timer is registered with [`register_timer`] for each deadline, and its callback injects the
+    /// timer interrupt.
+    #[default]
+    Callback,
+    /// No host timer is registered. The vCPU run loop reads the deadline with
+    /// [`EmulatedLocalApic::timer_deadline`](crate::EmulatedLocalApic::timer_deadline), arms the VMX-preemption timer
+    /// or a host hrtimer for it, and calls
+    /// [`EmulatedLocalApic::handle_timer_expiry`](crate::EmulatedLocalApic::handle_timer_expiry) when it expires.
+    External,
+}
+
 /// A virtual local APIC timer. (SDM Vol. 3C, Section 11.5.4)
 ///
 /// This struct virtualizes the access to 4 registers in the Local APIC:
@@ -63,7 +97,9 @@ pub struct ApicTimer {
     // internal states
     divide_shift: u8,
     last_start_ticks: u64,
+    deadline_ticks: u64,
     deadline_ns: u64,
+    backend: TimerBackend,
 
     // temporary fields untils we find a permanent place for apic and its timer
     cancel_token: Option<usize>,
@@ -79,27 +115,101 @@ impl ApicTimer {
 
             divide_shift: 1, // as `divide_configuration_register` is 0, the shift is 1 (divide by 2)
             last_start_ticks: 0,
+            deadline_ticks: 0,
             deadline_ns: 0,
+            backend: TimerBackend::Callback,
             cancel_token: None,
             where_am_i: (vm_id, vcpu_id),
         }
     }
 
-    // /// Check if an interrupt generated. if yes, update it's states.
-    // pub fn check_interrupt(&mut self) -> bool {
-    //     if self.deadline_ns == 0 {
-    //         false
-    //     } else if H::current_time_nanos() >= self.deadline_ns {
-    //         if self.is_periodic() {
-    //             self.deadline_ns += self.interval_ns();
-    //         } else {
-    //             self.deadline_ns = 0;
-    //         }
-    //         !self.is_masked()
-    //     } else {
-    //         false
-    //     }
-    // }
+    /// The backend used to wait for the deadline.
+    #[allow(dead_code)]
+    pub fn backend(&self) -> TimerBackend {
+        self.backend
+    }
+
+    /// Switch to another backend. A running timer is moved to the new backend with its deadline unchanged.
+    pub fn set_backend(&mut self, backend: TimerBackend) {
+        if self.backend == backend {
+            return;
+        }
+
+        self.retire_fired_timer();
+        if let Some(token) = self.cancel_token.take() {
+            time::cancel_timer(token);
+        }
+        self.backend = backend;
+        if self.is_started() {
+            self.arm_timer(self.deadline_ticks);
+        }
+    }
+
+    /// Stop a one-shot timer whose host timer has fired, or is about to fire, on [`TimerBackend::Callback`], so that
+    /// it's not armed again. The host timer callback can't update the timer states itself.
+    fn retire_fired_timer(&mut self) {
+        if self.has_fired() {
+            // Cancelling the host timer now could lose an interrupt that is about to be injected.
+            self.cancel_token = None;
+            self.last_start_ticks = 0;
+            self.deadline_ticks = 0;
+            self.deadline_ns = 0;
+        }
+    }
+
+    /// Whether this is a one-shot timer whose host timer has fired, or is about to fire, on
+    /// [`TimerBackend::Callback`].
+    fn has_fired(&self) -> bool {
+        self.backend == TimerBackend::Callback
+            && self.is_started()
+            && !self.is_periodic()
+            && current_ticks() >= self.deadline_ticks
+    }
+
+    /// The host tick at which the timer expires next, or `None` if the timer is stopped, or is a one-shot timer that
+    /// has fired.
+    pub fn next_deadline_ticks(&self) -> Option<u64> {
+        (self.is_started() && !self.has_fired()).then_some(self.deadline_ticks)
+    }
+
+    /// Check whether the deadline is reached, and if so, update the timer states. Only used by
+    /// [`TimerBackend::External`], as the host timer callback does this for [`TimerBackend::Callback`].
+    ///
+    /// A periodic timer is re-armed for the first period ending after now, a one-shot timer is stopped. Returns
+    /// whether a timer interrupt was injected, which is not the case if the deadline is not reached yet or the
+    /// interrupt is masked.
+    pub fn handle_expiry(&mut self) -> bool {
+        if self.backend != TimerBackend::External || !self.is_started() {
+            return false;
+        }
+
+        let now = current_ticks();
+        if now < self.deadline_ticks {
+            return false;
+        }
+
+        if self.is_periodic() {
+            let period = (self.initial_count_register as u64) << self.divide_shift;
+            let missed = (now - self.deadline_ticks) / period;
+            self.last_start_ticks = self.deadline_ticks + missed * period;
+            self.arm_timer(self.last_start_ticks + period);
+        } else {
+            self.last_start_ticks = 0;
+            self.deadline_ticks = 0;
+            self.deadline_ns = 0;
+        }
+
+        if self.is_masked() {
+            return false;
+        }
+
+        let (vm_id, vcpu_id) = self.where_am_i;
+        let vector = self.vector();
+        trace!("vlapic @ (vm {vm_id}, vcpu {vcpu_id}) timer expired, inject interrupt {vector}");
+        inject_interrupt(vm_id, vcpu_id, vector);
+
+        true
+    }
 
     #[allow(dead_code)]
     pub fn read_lvt(&self) -> u32 {
@@ -111,11 +221,18 @@ impl ApicTimer {
         const LVT_MASK: u32 = 0x0007_10FF;
 
         value &= LVT_MASK;
+
+        // the reserved timer mode is never stored, keep the previous mode instead
+        if LVT_TIMER::TimerMode.read(value) == TimerMode::Reserved as u32 {
+            warn!("reserved timer mode written to LVT Timer Register {value:#010X}, ignored");
+            value = (value & !LVT_TIMER::TimerMode::SET.mask())
+                | (self.lvt_timer_register.get() & LVT_TIMER::TimerMode::SET.mask());
+        }
+
         self.lvt_timer_register.set(value);
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn read_icr(&self) -> u32 {
         self.initial_count_register
     }
@@ -140,25 +257,9 @@ impl ApicTimer {
 
     /// Write to the Divide Configuration Register.
     pub fn write_dcr(&mut self, mut value: u32) {
-        const DCR_MASK: u32 = 0b1011;
-
         value &= DCR_MASK;
-        let shift = match value {
-            0b0000 => 1, // divide by 2
-            0b0001 => 2, // divide by 4
-            0b0010 => 3, // divide by 8
-            0b0011 => 4, // divide by 16
-            0b1000 => 5, // divide by 32
-            0b1001 => 6, // divide by 64
-            0b1010 => 7, // divide by 128
-            0b1011 => 0, // divide by 1
-            _ => unreachable!(
-                "internal error: invalid divide configuration register value after mask"
-            ),
-        };
-
         self.divide_configuration_register = value;
-        self.divide_shift = shift as u8;
+        self.divide_shift = divide_shift(value);
     }
 
     /// Current Count Register.
@@ -166,20 +267,23 @@ impl ApicTimer {
         if !self.is_started() {
             return 0;
         }
-        let remaining_ns = self.deadline_ns.wrapping_sub(time::current_time_nanos());
+        let remaining_ns = self.deadline_ns.saturating_sub(time::current_time_nanos());
         let remaining_ticks = time::nanos_to_ticks(remaining_ns);
         (remaining_ticks >> self.divide_shift) as _
     }
 
     /// Get the timer mode.
+    ///
+    /// [`Self::write_lvt`] never stores the reserved mode, it's reported as one-shot should it show up anyway.
     pub fn timer_mode(&self) -> TimerMode {
-        self.lvt_timer_register
-            .read_as_enum(LVT_TIMER::TimerMode)
-            .unwrap() // just panic if the value is invalid
+        match self.lvt_timer_register.read_as_enum(LVT_TIMER::TimerMode) {
+            Some(TimerMode::Periodic) => TimerMode::Periodic,
+            Some(TimerMode::TSCDeadline) => TimerMode::TSCDeadline,
+            _ => TimerMode::OneShot,
+        }
     }
 
     /// Check whether the timer interrupt is masked.
-    #[allow(dead_code)]
     pub fn is_masked(&self) -> bool {
         self.lvt_timer_register.is_set(LVT_TIMER::Mask)
     }
@@ -191,12 +295,15 @@ impl ApicTimer {
 
     /// Check whether the timer is started.
     pub fn is_started(&self) -> bool {
-        // these two conditions are equivalent actually, we check both for clarity and robustness
-        self.initial_count_register > 0 && self.cancel_token.is_some()
+        // `deadline_ticks` is only non-zero between `arm_timer` and `stop_timer` (or the expiry of a one-shot timer,
+        // noticed by `handle_expiry` or `retire_fired_timer`), we check both for clarity and robustness
+        self.initial_count_register > 0 && self.deadline_ticks != 0
     }
 
     /// Restart the timer. Will not start the timer if it is not started.
+    #[allow(dead_code)]
     pub fn restart_timer(&mut self) -> AxResult {
+        self.retire_fired_timer();
         if !self.is_started() {
             Ok(())
         } else {
@@ -215,15 +322,95 @@ impl ApicTimer {
         let deadline_ticks =
             current_ticks + ((self.initial_count_register as u64) << self.divide_shift);
         let (vm_id, vcpu_id) = self.where_am_i;
-        let vector = self.vector();
 
         trace!(
             "vlapic @ (vm {vm_id}, vcpu {vcpu_id}) starts timer @ tick {current_ticks:?}, deadline tick {deadline_ticks:?}"
         );
 
         self.last_start_ticks = current_ticks;
+        self.arm_timer(deadline_ticks);
+
+        Ok(())
+    }
+
+    /// Move a pending timer to the physical CPU this function is called on, keeping its deadline.
+    ///
+    /// [`register_timer`] arms the host timer on the calling CPU, so the timer keeps firing on the old CPU after the
+    /// vCPU is migrated. A timer whose deadline has already passed is left alone, as it has fired, or is about to
+    /// fire, on the old CPU, and a one-shot one is stopped.
+    pub fn migrate_timer(&mut self) -> AxResult {
+        self.retire_fired_timer();
+        if self.backend != TimerBackend::Callback
+            || !self.is_started()
+            || current_ticks() >= self.deadline_ticks
+        {
+            return Ok(());
+        }
+
+        time::cancel_timer(self.cancel_token.take().unwrap());
+        self.arm_timer(self.deadline_ticks);
+
+        Ok(())
+    }
+
+    /// Save the registers of the timer, and the time left until its deadline.
+    ///
+    /// With [`TimerBackend::Callback`], a timer whose deadline has already passed has fired, or is about to fire on
+    /// the host timer, so it's saved as stopped to avoid firing twice. With [`TimerBackend::External`], the expiry
+    /// is still to be handled and is saved with no time left.
+    pub fn save(&self) -> TimerState {
+        let remaining_ns = if self.is_started() && !self.has_fired() {
+            let remaining_ns = self.deadline_ns.saturating_sub(time::current_time_nanos());
+            (remaining_ns > 0 || self.backend == TimerBackend::External).then_some(remaining_ns)
+        } else {
+            None
+        };
+
+        TimerState {
+            lvt: self.lvt_timer_register.get(),
+            initial_count: self.initial_count_register,
+            divide_config: self.divide_configuration_register,
+            remaining_ns,
+        }
+    }
+
+    /// Restore the registers of the timer, and re-arm it for the time that was left when it was saved.
+    ///
+    /// A periodic timer keeps its phase: the current period is taken to end at the restored deadline.
+    pub fn restore(&mut self, state: &TimerState) -> AxResult {
+        if self.is_started() {
+            self.stop_timer()?;
+        }
+
+        self.write_lvt(state.lvt)?;
+        self.write_dcr(state.divide_config);
+        self.initial_count_register = state.initial_count;
+
+        if let Some(remaining_ns) = state.remaining_ns
+            && state.initial_count > 0
+        {
+            let period = (state.initial_count as u64) << self.divide_shift;
+            let deadline_ticks = current_ticks() + time::nanos_to_ticks(remaining_ns);
+            self.last_start_ticks = deadline_ticks.saturating_sub(period);
+            self.arm_timer(deadline_ticks);
+        }
+
+        Ok(())
+    }
+
+    /// Arm the timer for `deadline_ticks`. With [`TimerBackend::Callback`], the host timer is registered on the
+    /// current CPU.
+    fn arm_timer(&mut self, deadline_ticks: u64) {
+        let (vm_id, vcpu_id) = self.where_am_i;
+        let vector = self.vector();
+
+        self.deadline_ticks = deadline_ticks;
         self.deadline_ns = ticks_to_nanos(deadline_ticks);
 
+        if self.backend != TimerBackend::Callback {
+            return;
+        }
+
         self.cancel_token = Some(register_timer(
             ticks_to_time(deadline_ticks),
             Box::new(move |_| {
@@ -234,17 +421,19 @@ impl ApicTimer {
                 inject_interrupt(vm_id, vcpu_id, vector);
             }),
         ));
-
-        Ok(())
     }
 
     pub fn stop_timer(&mut self) -> AxResult {
         // TODO: maybe disable irq here?
+        self.retire_fired_timer();
         if self.is_started() {
             self.last_start_ticks = 0;
+            self.deadline_ticks = 0;
             self.deadline_ns = 0;
 
-            time::cancel_timer(self.cancel_token.take().unwrap());
+            if let Some(token) = self.cancel_token.take() {
+                time::cancel_timer(token);
+            }
         } else {
             warn!("`stop_timer` called when timer is not started, bad operation tolerated");
         }
@@ -300,9 +489,12 @@ impl ApicTimer {
 }
 
 #[cfg(test)]
+#[allow(clippy::unnecessary_cast)]
 mod tests {
     use crate::regs::lvt::LVT_TIMER::TimerMode::Value as TimerMode;
-    use crate::timer::ApicTimer;
+    use crate::state::TimerState;
+    use crate::test_utils::{advance_ticks, pending_timers, take_injected};
+    use crate::timer::{ApicTimer, TimerBackend};
     use axvisor_api::vmm::{VCpuId, VMId};
 
     #[test]
@@ -327,8 +519,8 @@ mod tests {
         let mut timer = ApicTimer::new(vm_id, vcpu_id);
 
         // Test LVT write with valid bits
-        assert!(timer.write_lvt(0x000710FF).is_ok());
-        assert_eq!(timer.read_lvt() & 0x000710FF, 0x000710FF);
+        assert!(timer.write_lvt(0x000510FF).is_ok());
+        assert_eq!(timer.read_lvt() & 0x000710FF, 0x000510FF);
 
         // Test LVT write with invalid bits (should be masked)
         assert!(timer.write_lvt(0xFFFFFFFF).is_ok());
@@ -374,6 +566,15 @@ mod tests {
         assert!(timer.write_lvt(0x20000).is_ok());
         assert_eq!(timer.timer_mode(), TimerMode::Periodic);
         assert!(timer.is_periodic());
+
+        // Reserved mode (0b11) keeps the previous mode, the other fields are still written
+        assert!(timer.write_lvt(0x60050).is_ok());
+        assert_eq!(timer.timer_mode(), TimerMode::Periodic);
+        assert_eq!(timer.vector(), 0x50);
+
+        // Set TSC-deadline mode (bit 18 = 1)
+        assert!(timer.write_lvt(0x40000).is_ok());
+        assert_eq!(timer.timer_mode(), TimerMode::TSCDeadline);
     }
 
     #[test]
@@ -394,6 +595,119 @@ mod tests {
         assert!(timer.is_masked());
     }
 
+    #[test]
+    fn test_migrate_stopped_timer() {
+        let vm_id = VMId::from(1 as usize);
+        let vcpu_id = VCpuId::from(0 as usize);
+        let mut timer = ApicTimer::new(vm_id, vcpu_id);
+
+        // Migrating a stopped timer is a no-op
+        assert!(timer.migrate_timer().is_ok());
+        assert!(!timer.is_started());
+    }
+
+    #[test]
+    fn test_migrate_pending_timer() {
+        let mut timer = ApicTimer::new(1, 0);
+        assert!(timer.write_lvt(0x30).is_ok()); // one-shot, vector 0x30
+        assert!(timer.write_icr(100).is_ok()); // 200 ticks with the default divide by 2
+        let deadline = timer.next_deadline_ticks().unwrap();
+        assert_eq!(pending_timers(), 1);
+
+        // The host timer is registered again on the new CPU, with the same deadline
+        advance_ticks(50);
+        assert!(timer.migrate_timer().is_ok());
+        assert_eq!(timer.next_deadline_ticks(), Some(deadline));
+        assert_eq!(pending_timers(), 1);
+
+        advance_ticks(149);
+        assert_eq!(take_injected(), []);
+        advance_ticks(1);
+        assert_eq!(take_injected(), [(1, 0, 0x30)]);
+        assert_eq!(pending_timers(), 0);
+    }
+
+    #[test]
+    fn test_timer_backend() {
+        let vm_id = VMId::from(1 as usize);
+        let vcpu_id = VCpuId::from(0 as usize);
+        let mut timer = ApicTimer::new(vm_id, vcpu_id);
+
+        // Default backend is the host timer callback
+        assert_eq!(timer.backend(), TimerBackend::Callback);
+
+        timer.set_backend(TimerBackend::External);
+        assert_eq!(timer.backend(), TimerBackend::External);
+
+        // A stopped timer has no deadline and never expires
+        assert_eq!(timer.next_deadline_ticks(), None);
+        assert!(!timer.handle_expiry());
+    }
+
+    #[test]
+    fn test_backend_switch_after_expiry() {
+        let mut timer = ApicTimer::new(1, 0);
+        assert!(timer.write_lvt(0x30).is_ok()); // one-shot, vector 0x30
+        assert!(timer.write_icr(100).is_ok());
+        advance_ticks(200);
+        assert_eq!(take_injected(), [(1, 0, 0x30)]);
+
+        // The fired one-shot timer is not armed again on the new backend
+        timer.set_backend(TimerBackend::External);
+        assert!(!timer.is_started());
+        assert_eq!(timer.next_deadline_ticks(), None);
+        assert!(!timer.handle_expiry());
+        assert_eq!(take_injected(), []);
+    }
+
+    #[test]
+    fn test_fired_one_shot_timer() {
+        let mut timer = ApicTimer::new(1, 0);
+        assert!(timer.write_lvt(0x30).is_ok()); // one-shot, vector 0x30
+        assert!(timer.write_icr(100).is_ok());
+        advance_ticks(200);
+        assert_eq!(take_injected(), [(1, 0, 0x30)]);
+
+        // The fired one-shot timer has no deadline, and is neither saved, moved nor restarted
+        assert_eq!(timer.next_deadline_ticks(), None);
+        assert_eq!(timer.save().remaining_ns, None);
+        assert!(timer.migrate_timer().is_ok());
+        assert!(!timer.is_started());
+        assert!(timer.restart_timer().is_ok());
+        assert!(timer.stop_timer().is_ok());
+        assert_eq!(pending_timers(), 0);
+        advance_ticks(200);
+        assert_eq!(take_injected(), []);
+    }
+
+    #[test]
+    fn test_save_restore_stopped_timer() {
+        let vm_id = VMId::from(1 as usize);
+        let mut timer = ApicTimer::new(vm_id, VCpuId::from(0 as usize));
+
+        // A stopped timer is saved without a deadline
+        assert!(timer.write_lvt(0x20050).is_ok()); // periodic, vector 0x50
+        timer.write_dcr(0b1011);
+        let state = timer.save();
+        assert_eq!(
+            state,
+            TimerState {
+                lvt: 0x20050,
+                initial_count: 0,
+                divide_config: 0b1011,
+                remaining_ns: None,
+            }
+        );
+
+        // Restoring brings back the registers, and leaves the timer stopped
+        let mut restored = ApicTimer::new(vm_id, VCpuId::from(0 as usize));
+        assert!(restored.restore(&state).is_ok());
+        assert_eq!(restored.save(), state);
+        assert!(restored.is_periodic());
+        assert_eq!(restored.vector(), 0x50);
+        assert!(!restored.is_started());
+    }
+
     #[test]
     fn test_multiple_timers() {
         let vm_id = VMId::from(1 as usize);
@@ -12,6 +12,70 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use alloc::{vec, vec::Vec};
+
+/// A set of vCPUs, e.g. the destination of an interrupt, as a bitmap indexed by vCPU ID.
+///
+/// The bitmap is sized for the number of vCPUs of the VM, so it scales to x2APIC-sized VMs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VCpuSet {
+    bits: Vec<u64>,
+    vcpu_num: usize,
+}
+
+impl VCpuSet {
+    /// An empty set, for a VM with `vcpu_num` vCPUs.
+    pub fn new(vcpu_num: usize) -> Self {
+        Self {
+            bits: vec![0; vcpu_num.div_ceil(64)],
+            vcpu_num,
+        }
+    }
+
+    /// The set of all the `vcpu_num` vCPUs.
+    pub fn full(vcpu_num: usize) -> Self {
+        let mut set = Self::new(vcpu_num);
+        set.bits.fill(u64::MAX);
+        if !vcpu_num.is_multiple_of(64) {
+            *set.bits.last_mut().unwrap() = (1 << (vcpu_num % 64)) - 1;
+        }
+        set
+    }
+
+    /// Add `vcpu_id` to the set. IDs out of the VM are ignored.
+    pub fn insert(&mut self, vcpu_id: usize) {
+        if vcpu_id < self.vcpu_num {
+            self.bits[vcpu_id >> 6] |= 1 << (vcpu_id & 0x3f);
+        }
+    }
+
+    /// Remove `vcpu_id` from the set.
+    pub fn remove(&mut self, vcpu_id: usize) {
+        if vcpu_id < self.vcpu_num {
+            self.bits[vcpu_id >> 6] &= !(1 << (vcpu_id & 0x3f));
+        }
+    }
+
+    /// The lowest vCPU ID in the set.
+    pub fn first(&self) -> Option<usize> {
+        self.iter().next()
+    }
+
+    /// The vCPU IDs in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bits.iter().enumerate().flat_map(|(i, &word)| {
+            let mut word = word;
+            core::iter::from_fn(move || {
+                (word != 0).then(|| {
+                    let bit = word.trailing_zeros() as usize;
+                    word &= word - 1;
+                    (i << 6) | bit
+                })
+            })
+        })
+    }
+}
+
 /// Find the last (most significant) bit set in a 32-bit value.
 ///
 /// Bits are numbered starting at 0 (the least significant bit).
@@ -58,6 +122,35 @@ mod tests {
         assert_eq!(fls32(0x7FFFFFFF), 30);
     }
 
+    #[test]
+    fn test_vcpu_set() {
+        // Empty set
+        let mut set = VCpuSet::new(1024);
+        assert_eq!(set.first(), None);
+
+        // vCPUs beyond 64 are representable
+        set.insert(1000);
+        set.insert(64);
+        set.insert(3);
+        assert_eq!(set.first(), Some(3));
+        assert_eq!(set.iter().collect::<Vec<_>>(), [3, 64, 1000]);
+
+        // Out of range IDs are ignored
+        set.insert(1024);
+        assert_eq!(set.iter().last(), Some(1000));
+
+        set.remove(3);
+        assert_eq!(set.first(), Some(64));
+
+        // Full set without vCPUs out of the VM
+        let mut set = VCpuSet::full(130);
+        assert_eq!(set.iter().count(), 130);
+        assert_eq!(set.iter().last(), Some(129));
+        set.remove(0);
+        assert_eq!(set.first(), Some(1));
+        assert_eq!(VCpuSet::full(128).iter().count(), 128);
+    }
+
     #[test]
     fn test_fls32_edge_cases() {
         // Test case: input is 0x00000010, bit 4 is set
@@ -12,24 +12,35 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use alloc::sync::Arc;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering};
 
-use axvisor_api::vmm::{VCpuId, VMId};
+use axvisor_api::vmm::VCpuId;
 use bit::BitIndex;
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
-use axaddrspace::{HostPhysAddr, device::AccessWidth};
-use axerrno::{AxError, AxResult, ax_err_type};
-use axvisor_api::{memory::PhysFrame, vmm};
+use axaddrspace::{GuestPhysAddr, HostPhysAddr, HostVirtAddr, device::AccessWidth};
+use axerrno::{AxError, AxResult, ax_err, ax_err_type};
+use axvisor_api::{
+    memory::{self, PhysFrame},
+    vmm,
+};
 
+use crate::apicv::{ApicvFeatures, LVT_CMCI_MSR, X2ApicMsrIntercepts};
+use crate::avic::{IncompleteIpiCause, decode_noaccel, is_noaccel_trap};
+use crate::bus::{ApicBus, WireMode};
+use crate::consts::x2apic::x2apic_ldr;
+use crate::consts::xapic::{DEFAULT_APIC_BASE, xapic_mmio_access_reg_offset};
 use crate::consts::{
-    APIC_LVT_DS, APIC_LVT_M, APIC_LVT_VECTOR, ApicRegOffset, LAPIC_TRIG_EDGE,
-    RESET_SPURIOUS_INTERRUPT_VECTOR,
+    APIC_LVT_DS, APIC_LVT_M, APIC_LVT_VECTOR, ApicRegOffset, LAPIC_TRIG_EDGE, LAPIC_TRIG_LEVEL,
+    NMI_VECTOR, RESET_DFR, RESET_LVT_REG, RESET_SPURIOUS_INTERRUPT_VECTOR,
 };
+use crate::profile::{AmdExtApic, LapicProfile, MAX_EXT_LVT_COUNT};
+use crate::pv_eoi::{PV_EOI_MSR_ADDR_MASK, PvEoi};
 use crate::regs::{
-    APIC_BASE, ApicBaseRegisterMsr,
-    DESTINATION_FORMAT::{self, Model::Value as APICDestinationFormat},
-    ERROR_STATUS, ErrorStatusRegisterLocal, ErrorStatusRegisterValue, INTERRUPT_COMMAND_HIGH,
+    APIC_BASE, ApicBaseRegisterMsr, ERROR_STATUS, EXTENDED_APIC_CONTROL, ErrorStatusRegisterLocal,
+    ErrorStatusRegisterValue, INTERRUPT_COMMAND_HIGH,
     INTERRUPT_COMMAND_LOW::{
         self, DeliveryMode::Value as APICDeliveryMode,
         DestinationShorthand::Value as APICDestination,
@@ -38,13 +49,26 @@ use crate::regs::{
     SpuriousInterruptVectorRegisterLocal,
     lvt::{
         LVT_CMCI, LVT_ERROR, LVT_LINT0, LVT_LINT1, LVT_PERFORMANCE_COUNTER, LVT_THERMAL_MONITOR,
-        LVT_TIMER, LocalVectorTable,
+        LVT_TIMER, LocalVectorTable, LvtLint0RegisterLocal,
     },
 };
-use crate::{timer::ApicTimer, utils::fls32};
+use crate::state::{
+    LAPIC_EXT_REG_SLOTS, LAPIC_REG_SLOTS, LAPIC_STATE_VERSION, LapicState, TimerState,
+};
+use crate::timer::{ApicTimer, TimerBackend};
+use crate::utils::{VCpuSet, fls32};
 
 pub use crate::regs::lvt::LVT_TIMER::TimerMode::Value as TimerMode;
 
+/// The local interrupt pins of the local APIC, configured by the LVT LINT0 and LVT LINT1 registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintPin {
+    /// LINT0, usually connected to the INTR output of the 8259 PIC.
+    Lint0 = 0,
+    /// LINT1, usually connected to the NMI source of the platform.
+    Lint1 = 1,
+}
+
 /// Virtual-APIC Registers.
 pub struct VirtualApicRegs {
     /// The virtual-APIC page is a 4-KByte region of memory
@@ -53,11 +77,25 @@ pub struct VirtualApicRegs {
     /// a 64-bit VM-execution control field in the VMCS (see Section 25.6.8).
     virtual_lapic: NonNull<LocalAPICRegs>,
 
+    bus: Arc<ApicBus>,
     /// Todo: distinguish between APIC ID and vCPU ID.
     vapic_id: u32,
     esr_pending: ErrorStatusRegisterLocal,
     esr_firing: i32,
 
+    /// Whether LINT0 and LINT1 are asserted, with the pin polarity applied.
+    lint_asserted: [bool; 2],
+    /// Vectors routed to this local APIC as level-triggered interrupts, which need an EOI exit.
+    level_vectors: [u64; 4],
+    /// APIC virtualization controls used by the VMM.
+    apicv: ApicvFeatures,
+    /// The local APIC model presented to the guest.
+    profile: LapicProfile,
+    /// KVM paravirtual EOI.
+    pv_eoi: PvEoi,
+    /// Whether the local APIC was reset by an INIT since the VMM last checked with [`Self::take_init`].
+    init_received: bool,
+
     virtual_timer: ApicTimer,
 
     /// Vector number for the highest priority bit that is set in the ISR
@@ -74,15 +112,43 @@ pub struct VirtualApicRegs {
     apic_page: PhysFrame,
 }
 
+impl Drop for VirtualApicRegs {
+    fn drop(&mut self) {
+        // The page is freed with `apic_page`, withdraw it from the AVIC physical APIC ID table, and from the other
+        // CPUs, waiting for those accessing it.
+        self.bus.remove_avic_dest(self.vapic_id);
+        self.bus.set_apic_page(self.vapic_id, core::ptr::null_mut());
+    }
+}
+
+// SAFETY: the virtual-APIC page is owned by `apic_page` and only accessed through `virtual_lapic`, so moving
+// `VirtualApicRegs` to another thread moves the exclusive access to the page with it. The paravirtual EOI flag of
+// `pv_eoi` points into guest memory, which outlives the vCPU, and is only accessed with atomic operations, so it may
+// be accessed from any thread.
+unsafe impl Send for VirtualApicRegs {}
+
 impl VirtualApicRegs {
     /// Create new virtual-APIC registers by allocating a 4-KByte page for the virtual-APIC page.
-    pub fn new(vm_id: VMId, vcpu_id: VCpuId) -> Self {
-        let apic_frame = PhysFrame::alloc_zero().expect("allocate virtual-APIC page failed");
-        Self {
+    ///
+    /// Fails with `InvalidInput` if `vcpu_id` is out of range of the APIC bus.
+    pub fn new(bus: Arc<ApicBus>, vcpu_id: VCpuId) -> AxResult<Self> {
+        if vcpu_id >= bus.vcpu_num() {
+            return ax_err!(InvalidInput, "vCPU ID out of range of the APIC bus");
+        }
+        let apic_frame = PhysFrame::alloc_zero()?;
+        let vm_id = bus.vm_id();
+        let mut regs = Self {
+            bus,
             // virtual-APIC ID is the same as the VCPU ID.
             vapic_id: vcpu_id as _,
             esr_pending: ErrorStatusRegisterLocal::new(0),
             esr_firing: 0,
+            lint_asserted: [false; 2],
+            level_vectors: [0; 4],
+            apicv: ApicvFeatures::default(),
+            profile: LapicProfile::default(),
+            pv_eoi: PvEoi::default(),
+            init_received: false,
             virtual_lapic: NonNull::new(apic_frame.as_mut_ptr().cast()).unwrap(),
             apic_page: apic_frame,
             svr_last: SpuriousInterruptVectorRegisterLocal::new(RESET_SPURIOUS_INTERRUPT_VECTOR),
@@ -90,7 +156,97 @@ impl VirtualApicRegs {
             isrv: 0,
             apic_base: ApicBaseRegisterMsr::new(0),
             virtual_timer: ApicTimer::new(vm_id, vcpu_id),
+        };
+        regs.bus
+            .set_apic_page(regs.vapic_id, regs.virtual_lapic.as_ptr().cast());
+        regs.reset_power_on()?;
+        Ok(regs)
+    }
+
+    /// 11.4.7.1 Local APIC State After Power-Up or Reset
+    /// Put the local APIC in its power-up state: enabled in xAPIC mode at the default base address, with the BSP
+    /// flag set for vCPU 0, and software-disabled.
+    pub fn reset_power_on(&mut self) -> AxResult {
+        let mut apic_base = ApicBaseRegisterMsr::new(DEFAULT_APIC_BASE as u64);
+        apic_base.modify(APIC_BASE::XAPIC_ENABLED::SET);
+        if self.vapic_id == 0 {
+            apic_base.modify(APIC_BASE::BSP::SET);
+        }
+        self.reset(apic_base)
+    }
+
+    /// 11.4.7.3 Local APIC State After an INIT Reset ("Wait-for-SIPI" State)
+    /// Put the local APIC in its state after an INIT: as after power-up, except for the APIC ID and the
+    /// IA32_APIC_BASE MSR, which keep their values. Per 11.12.5.1, a local APIC in x2APIC mode stays in x2APIC mode.
+    pub fn reset_init(&mut self) -> AxResult {
+        self.reset(self.apic_base)
+    }
+
+    /// Reset every register to its power-up value, with the IA32_APIC_BASE MSR set to `apic_base`:
+    ///
+    /// - IRR, ISR, TMR, ICR, LDR, TPR, ESR and the timer counts are 0, and the timer is stopped,
+    /// - DFR is all 1s, SVR is 0FFH (software-disabled), and all LVT entries are masked,
+    /// - ID holds the APIC ID, and Version the version of the local APIC.
+    ///
+    /// Interrupts and INITs pending from other CPUs are discarded.
+    fn reset(&mut self, apic_base: ApicBaseRegisterMsr) -> AxResult {
+        let was_enabled = self.is_software_enabled();
+        let lint0 = self.lint_lvt(LintPin::Lint0);
+        let lint0_extint =
+            !lint0.is_set(LVT_LINT0::Mask) && lint0.matches_all(LVT_LINT0::DeliveryMode::ExtINT);
+
+        self.bus.pi_desc(self.vapic_id).take_pir();
+        self.bus.take_remote(self.vapic_id);
+        self.bus.take_init(self.vapic_id);
+        self.init_received = false;
+
+        for index in 0..LAPIC_REG_SLOTS + LAPIC_EXT_REG_SLOTS {
+            self.set_page_slot(index, 0);
         }
+        self.apic_base = apic_base;
+        self.regs().ID.set(self.id_reg(apic_base));
+        self.regs().VERSION.set(self.profile.version_reg());
+        self.regs().DFR.set(RESET_DFR);
+        self.regs().SVR.set(RESET_SPURIOUS_INTERRUPT_VECTOR);
+        self.regs().LVT_CMCI.set(RESET_LVT_REG);
+        self.regs().LVT_TIMER.set(RESET_LVT_REG);
+        self.regs().LVT_THERMAL.set(RESET_LVT_REG);
+        self.regs().LVT_PMI.set(RESET_LVT_REG);
+        self.regs().LVT_LINT0.set(RESET_LVT_REG);
+        self.regs().LVT_LINT1.set(RESET_LVT_REG);
+        self.regs().LVT_ERROR.set(RESET_LVT_REG);
+        self.regs().EXT_FEATURE.set(self.ext_feature_reg());
+        for lvt in &self.regs().EXT_LVT {
+            lvt.set(RESET_LVT_REG as _);
+        }
+
+        self.svr_last.set(RESET_SPURIOUS_INTERRUPT_VECTOR);
+        self.lvt_last = LocalVectorTable::default();
+        self.esr_pending.set(0);
+        self.esr_firing = 0;
+        self.isrv = 0;
+        // Clear the flag in guest memory before forgetting it, the EOI it may signal is moot with the ISR cleared.
+        self.pv_eoi.take_pending();
+        self.pv_eoi = PvEoi::default();
+        self.virtual_timer.restore(&TimerState {
+            lvt: RESET_LVT_REG,
+            ..Default::default()
+        })?;
+
+        self.publish_dest();
+        // As for the guest masking LINT0 and software-disabling the local APIC, see `write_lvt` and `write_svr`.
+        if lint0_extint
+            && self
+                .bus
+                .switch_wire_mode(&[WireMode::Lapic], WireMode::Null)
+        {
+            debug!("[VLAPIC] vpic wire mode changed to NULL");
+        }
+        if was_enabled && self.bus.switch_wire_mode(&[WireMode::Null], WireMode::Intr) {
+            debug!("[VLAPIC] vpic wire mode changed to INTR");
+        }
+
+        Ok(())
     }
 
     const fn regs(&self) -> &LocalAPICRegs {
@@ -105,6 +261,11 @@ impl VirtualApicRegs {
         self.apic_page.start_paddr()
     }
 
+    /// The physical address of the APIC-access page of the VM.
+    pub fn apic_access_addr(&self) -> HostPhysAddr {
+        self.bus.apic_access_addr()
+    }
+
     /// Gets the APIC base MSR value.
     #[allow(dead_code)]
     pub fn apic_base(&self) -> u64 {
@@ -124,6 +285,16 @@ impl VirtualApicRegs {
             && !self.apic_base.is_set(APIC_BASE::X2APIC_Enabled)
     }
 
+    /// Returns whether the local APIC is software-enabled through the SVR.
+    ///
+    /// 11.4.7.2 Local APIC State After It Has Been Software Disabled
+    /// While software-disabled, the local APIC only accepts NMI, INIT and SIPI messages, and the mask bits of all
+    /// LVT entries are set and can't be cleared. Interrupts already in the IRR and ISR are held and still delivered.
+    pub fn is_software_enabled(&self) -> bool {
+        self.svr_last
+            .is_set(SPURIOUS_INTERRUPT_VECTOR::APICSoftwareEnableDisable)
+    }
+
     /// Returns the current timer mode.
     pub fn timer_mode(&self) -> AxResult<TimerMode> {
         self.regs()
@@ -132,6 +303,26 @@ impl VirtualApicRegs {
             .ok_or_else(|| ax_err_type!(InvalidData, "Failed to read timer mode from LVT_TIMER"))
     }
 
+    /// Move the pending APIC timer to the physical CPU this function is called on.
+    pub fn migrate_timer(&mut self) -> AxResult {
+        self.virtual_timer.migrate_timer()
+    }
+
+    /// Select the backend used by the APIC timer.
+    pub fn set_timer_backend(&mut self, backend: TimerBackend) {
+        self.virtual_timer.set_backend(backend);
+    }
+
+    /// The host tick at which the APIC timer expires next, if it is running.
+    pub fn timer_deadline(&self) -> Option<u64> {
+        self.virtual_timer.next_deadline_ticks()
+    }
+
+    /// Fire the APIC timer if its deadline is reached. Returns whether an interrupt was injected.
+    pub fn handle_timer_expiry(&mut self) -> bool {
+        self.virtual_timer.handle_expiry()
+    }
+
     /// 30.1.4 EOI Virtualization
     /// IF any bits set in VISR
     ///     THEN SVI := highest index of bit set in VISR
@@ -139,8 +330,8 @@ impl VirtualApicRegs {
     /// FI;
     fn find_isrv(&self) -> u32 {
         let mut isrv = 0;
-        /* i ranges effectively from 7 to 1 */
-        for i in (1..8).rev() {
+        /* vectors 0 to 15 are never accepted, but 16 to 31 are, so i ranges from 7 to 0 */
+        for i in (0..8).rev() {
             let val = self.regs().ISR[i].get() as u32;
             if val != 0 {
                 isrv = ((i as u32) << 5) | fls32(val) as u32;
@@ -151,6 +342,25 @@ impl VirtualApicRegs {
         isrv
     }
 
+    /// Vector number for the highest priority bit that is set in the IRR, or 0 if the IRR is empty.
+    ///
+    /// With the AMD Interrupt Enable registers enabled, interrupts of vectors whose IER bit is clear are held in the
+    /// IRR.
+    fn find_irrv(&self) -> u32 {
+        let ier_enabled = self.regs().EXT_CONTROL.is_set(EXTENDED_APIC_CONTROL::IerEn);
+        for i in (0..8).rev() {
+            let mut val = self.regs().IRR[i].get() as u32;
+            if ier_enabled {
+                val &= self.regs().IER[i].get() as u32;
+            }
+            if val != 0 {
+                return ((i as u32) << 5) | fls32(val) as u32;
+            }
+        }
+
+        0
+    }
+
     fn update_ppr(&mut self) {
         let isrv = self.isrv;
         let tpr = self.regs().TPR.get();
@@ -175,6 +385,31 @@ impl VirtualApicRegs {
             return;
         }
 
+        self.eoi_vector(vector);
+    }
+
+    /// AMD Specific End Of Interrupt: end the interrupt with `vector` rather than the highest priority one in
+    /// service. Nothing happens if `vector` is not in service.
+    fn process_seoi(&mut self, vector: u32) {
+        let (idx, bitpos) = extract_index_and_bitpos_u32(vector);
+        if (self.regs().ISR[idx].get() as u32).bit(bitpos) {
+            self.eoi_vector(vector);
+        } else {
+            debug!("[VLAPIC] SEOI of vector {vector:#x} not in service ignored");
+        }
+    }
+
+    /// 30.1.4 EOI Virtualization
+    /// Complete the EOI of a virtualized EOI for `vector`, for which the EOI-exit bitmap requested a VM exit.
+    ///
+    /// The processor has already cleared the VISR bit and updated SVI and VPPR on the virtual-APIC page before the
+    /// EOI-induced VM exit, so this only recomputes them and performs the side effects of the EOI.
+    pub fn handle_virtual_eoi(&mut self, vector: u32) {
+        self.eoi_vector(vector);
+    }
+
+    /// Signal the end of the interrupt with `vector`.
+    fn eoi_vector(&mut self, vector: u32) {
         let (idx, bitpos) = extract_index_and_bitpos_u32(vector);
 
         // Upon receiving an EOI, the APIC clears the highest priority bit in the ISR
@@ -198,123 +433,482 @@ impl VirtualApicRegs {
         // If a TMR bit is set when an EOI cycle for its corresponding interrupt vector is generated, an EOI message is sent to all I/O APICs.
         // (see 11.8.4 Interrupt Acceptance for Fixed Interrupts)
         if (self.regs().TMR[idx].get() as u32).bit(bitpos) {
+            // A level-triggered interrupt from LINT0 is completed locally.
+            self.lint0_eoi(vector);
+
             // Send EOI to all I/O APICs
             /*
              * Per Intel SDM 10.8.5, Software can inhibit the broadcast of
              * EOI by setting bit 12 of the Spurious Interrupt Vector
              * Register of the LAPIC.
-             * TODO: Check if the bit 12 "Suppress EOI Broadcasts" is set.
              */
-            unimplemented!("vioapic_broadcast_eoi(vlapic2vcpu(vlapic)->vm, vector);")
+            if !self
+                .svr_last
+                .is_set(SPURIOUS_INTERRUPT_VECTOR::EOIBroadcastSuppression)
+            {
+                self.bus.broadcast_eoi(vector as u8);
+            }
         }
 
-        debug!("Gratuitous EOI vector: {vector:#010X}");
+        // The next pending interrupt, if any, is picked up by `pending_intr` before the next VM entry.
+        debug!("[VLAPIC] EOI vector: {vector:#010X}");
+    }
 
-        unimplemented!("vcpu_make_request(vlapic2vcpu(vlapic), ACRN_REQUEST_EVENT);")
+    /// Accept a fixed interrupt into the IRR, setting the TMR bit for level-triggered interrupts.
+    ///
+    /// 11.8.4 Interrupt Acceptance for Fixed Interrupts
+    /// Interrupts are not accepted while the local APIC is software-disabled, and illegal vectors (0 to 15) are
+    /// rejected with a Receive Illegal Vector error. Returns whether the interrupt was accepted.
+    pub fn accept_intr(&mut self, vector: u32, level: bool) -> bool {
+        if !self.is_software_enabled() {
+            debug!(
+                "[VLAPIC] vlapic [{}] is software-disabled, ignoring interrupt {vector:#x}",
+                self.vapic_id
+            );
+            return false;
+        }
+
+        if vector < 16 {
+            self.set_err(ERROR_STATUS::ReceiveIllegalVector::SET);
+            debug!("[VLAPIC] vlapic ignoring interrupt to vector {vector}");
+            return false;
+        }
+
+        let (idx, bitpos) = extract_index_and_bitpos_u32(vector);
+
+        self.irr(idx).fetch_or(1 << bitpos, Ordering::SeqCst);
+
+        let mut tmr = self.regs().TMR[idx].get() as u32;
+        tmr.set_bit(bitpos, level);
+        self.regs().TMR[idx].set(tmr as _);
+
+        true
     }
 
-    /// Post an interrupt to the vcpu running on 'hostcpu'.
-    /// This will use a hardware assist if available (e.g. Posted Interrupt)
-    /// or fall back to sending an 'ipinum' to interrupt the 'hostcpu'.
-    fn set_err(&mut self, mask: ErrorStatusRegisterValue) {
-        self.esr_pending.modify(mask);
+    /// Record the APIC virtualization controls used by the VMM.
+    pub fn set_apicv_features(&mut self, features: ApicvFeatures) {
+        self.apicv = features;
+    }
 
-        self.esr_firing = 1;
-        if self.esr_firing == 0 {
-            self.esr_firing = 1;
-            let _lvt = self.regs().LVT_ERROR.get();
-            //  if ((lvt & APIC_LVT_M) == 0U) {
-            //     vec = lvt & APIC_LVT_VECTOR;
-            //     if (vec >= 16U) {
-            //         vlapic_accept_intr(vlapic, vec, LAPIC_TRIG_EDGE);
-            //     }
-            // }
-            unimplemented!("vlapic_accept_intr(vlapic, vec, LAPIC_TRIG_EDGE)");
-            // self.esr_firing = 0;
+    /// Select the local APIC model presented to the guest, and update the Version register accordingly.
+    pub fn set_profile(&mut self, profile: LapicProfile) {
+        self.profile = profile;
+        self.regs().VERSION.set(profile.version_reg());
+        self.regs().EXT_FEATURE.set(self.ext_feature_reg());
+        self.regs()
+            .EXT_CONTROL
+            .set(self.regs().EXT_CONTROL.get() & self.ext_control_writable());
+        if !profile.directed_eoi {
+            self.svr_last
+                .modify(SPURIOUS_INTERRUPT_VECTOR::EOIBroadcastSuppression::CLEAR);
+            self.regs()
+                .SVR
+                .modify(SPURIOUS_INTERRUPT_VECTOR::EOIBroadcastSuppression::CLEAR);
         }
     }
 
-    fn is_dest_field_matched(&self, dest: u32) -> AxResult<bool> {
-        let mut ret = false;
+    /// The Extended APIC Feature register of the profile, 0 without the AMD extended register space.
+    fn ext_feature_reg(&self) -> u32 {
+        self.profile
+            .amd_ext
+            .map_or(0, |amd_ext| amd_ext.feature_reg())
+    }
 
-        let ldr = self.regs().LDR.get();
+    /// The bits of the Extended APIC Control register enabling features implemented by the profile. Extended APIC
+    /// IDs are not supported, as APIC IDs are vCPU IDs.
+    fn ext_control_writable(&self) -> u32 {
+        self.profile.amd_ext.map_or(0, |amd_ext| {
+            let mut writable = 0;
+            if amd_ext.ier {
+                writable |= EXTENDED_APIC_CONTROL::IerEn::SET.value;
+            }
+            if amd_ext.seoi {
+                writable |= EXTENDED_APIC_CONTROL::SeoiEn::SET.value;
+            }
+            writable
+        })
+    }
 
-        if self.is_x2apic_enabled() {
-            return Ok(true);
-        } else {
-            match self
-                .regs()
-                .DFR
-                .read_as_enum::<APICDestinationFormat>(DESTINATION_FORMAT::Model)
-                .ok_or(AxError::InvalidData)?
-            {
-                APICDestinationFormat::Flat => {
-                    /*
-                     * In the "Flat Model" the MDA is interpreted as an 8-bit wide
-                     * bitmask. This model is available in the xAPIC mode only.
-                     */
-                    let logical_id = ldr >> 24;
-                    let dest_logical_id = dest & 0xff;
-                    if logical_id & dest_logical_id != 0 {
-                        ret = true;
-                    }
-                }
-                APICDestinationFormat::Cluster => {
-                    /*
-                     * In the "Cluster Model" the MDA is used to identify a
-                     * specific cluster and a set of APICs in that cluster.
-                     */
-                    let logical_id = (ldr >> 24) & 0xf;
-                    let cluster_id = ldr >> 28;
-                    let dest_logical_id = dest & 0xf;
-                    let dest_cluster_id = (dest >> 4) & 0xf;
-                    if (cluster_id == dest_cluster_id) && ((logical_id & dest_logical_id) != 0) {
-                        ret = true;
-                    }
-                }
+    /// The AMD extended registers of the profile, if it implements the register at `offset`.
+    ///
+    /// Accesses to the extended registers the profile does not implement are ignored in xAPIC mode, where they are
+    /// reserved, and fail in x2APIC mode, where the MSRs do not exist.
+    fn amd_ext_reg(&self, offset: ApicRegOffset) -> AxResult<Option<AmdExtApic>> {
+        let amd_ext = self.profile.amd_ext.filter(|amd_ext| match offset {
+            ApicRegOffset::SEOI => amd_ext.seoi,
+            ApicRegOffset::IER(_) => amd_ext.ier,
+            ApicRegOffset::ExtLvt(index) => {
+                index.as_usize() < amd_ext.ext_lvt_count.min(MAX_EXT_LVT_COUNT) as usize
+            }
+            _ => true,
+        });
+        if amd_ext.is_none() {
+            warn!("[VLAPIC] access {offset} register: not implemented by the profile");
+            if self.is_x2apic_enabled() {
+                return Err(AxError::InvalidInput);
             }
         }
-        Ok(ret)
+        Ok(amd_ext)
     }
 
-    /// This function populates 'dmask' with the set of vcpus that match the
-    /// addressing specified by the (dest, phys, lowprio) tuple.
-    fn calculate_dest_no_shorthand(
-        &self,
-        is_broadcast: bool,
-        dest: u32,
-        is_phys: bool,
-        lowprio: bool,
-    ) -> AxResult<u64> {
-        let mut dmask = 0;
-
-        if is_broadcast {
-            // Broadcast in both logical and physical modes.
-            dmask = vmm::current_vm_active_vcpus() as u64;
-        } else if is_phys {
-            // Physical mode: "dest" is local APIC ID.
-            // Todo: distinguish between APIC ID and vCPU ID.
-            dmask = 1 << dest;
-        } else if lowprio {
-            // lowprio is not supported.
-            // Refer to 11.6.2.4 Lowest Priority Delivery Mode.
-            unimplemented!("lowprio");
+    /// The x2APIC MSR intercepts for the current mode and APIC virtualization controls.
+    ///
+    /// Reads of the LVT CMCI register are intercepted if the profile does not implement it, so that they raise #GP.
+    pub fn x2apic_msr_intercepts(&self) -> X2ApicMsrIntercepts {
+        let mut intercepts = X2ApicMsrIntercepts::new(self.apicv, self.is_x2apic_enabled());
+        if !self.profile.cmci {
+            intercepts.intercept_read(LVT_CMCI_MSR);
+        }
+        intercepts
+    }
+
+    /// 30.1.2 TPR Virtualization
+    /// The TPR threshold (bits 3:0) for the “use TPR shadow” VM-execution control: the priority class of the highest
+    /// pending interrupt if the VTPR masks it, so that the guest lowering VTPR below it causes a VM exit, else 0.
+    pub fn tpr_threshold(&self) -> u32 {
+        let vector = self.find_irrv();
+        if vector != 0 && prio(vector) <= prio(self.regs().TPR.get()) {
+            prio(vector)
         } else {
-            // Logical mode: "dest" is message destination addr
-            // to be compared with the logical APIC ID in LDR.
-
-            let vcpu_mask = vmm::active_vcpus(vmm::current_vm_id()).unwrap();
-            for i in 0..vmm::current_vm_vcpu_num() {
-                if vcpu_mask & (1 << i) != 0 {
-                    if !self.is_dest_field_matched(dest)? {
-                        continue;
-                    }
-                    dmask |= 1 << i;
+            0
+        }
+    }
+
+    /// 30.1.2 TPR Virtualization
+    /// Handle a TPR-below-threshold VM exit, after the guest lowered the VTPR on the virtual-APIC page.
+    ///
+    /// The PPR is recomputed from the VTPR, and the interrupt that became deliverable, if any, is returned.
+    pub fn handle_tpr_below_threshold(&mut self) -> Option<u8> {
+        let tpr = self.regs().TPR.get() & 0xff;
+        self.regs().TPR.set(tpr);
+        self.update_ppr();
+        debug!("[VLAPIC] TPR below threshold, TPR {tpr:#x}");
+        self.pending_intr()
+    }
+
+    /// 30.2.1 Evaluation of Pending Virtual Interrupts
+    /// The guest interrupt status (16 bits) for virtual-interrupt delivery: RVI, the vector of the highest priority
+    /// interrupt in the IRR, in the low byte, and SVI, the vector of the highest priority interrupt in the ISR, in the
+    /// high byte.
+    pub fn guest_interrupt_status(&self) -> u16 {
+        let rvi = self.find_irrv() as u16;
+        let svi = self.find_isrv() as u16;
+        (svi << 8) | rvi
+    }
+
+    /// 30.1.4 EOI Virtualization
+    /// The EOI-exit bitmap (256 bits), selecting the vectors whose virtualized EOI causes a VM exit.
+    ///
+    /// These are the level-triggered vectors, whose EOI must be broadcast or reset a Remote IRR: those with their
+    /// TMR bit set, the vector of a level-triggered LINT0, and those registered with
+    /// [`Self::set_level_triggered_vector`].
+    pub fn eoi_exit_bitmap(&self) -> [u64; 4] {
+        let mut bitmap = self.level_vectors;
+
+        for i in 0..8 {
+            let tmr = self.regs().TMR[i].get() as u32;
+            bitmap[i >> 1] |= (tmr as u64) << ((i & 1) << 5);
+        }
+
+        let lint0 = self.lint_lvt(LintPin::Lint0);
+        if lint0
+            .matches_all(LVT_LINT0::DeliveryMode::Fixed + LVT_LINT0::TriggerMode::LevelSensitive)
+        {
+            let vector = lint0.read(LVT_LINT0::Vector);
+            bitmap[vector as usize >> 6] |= 1 << (vector & 0x3f);
+        }
+
+        bitmap
+    }
+
+    /// Mark `vector` as routed level-triggered to this local APIC (e.g. by an I/O APIC), so that its EOI exits.
+    pub fn set_level_triggered_vector(&mut self, vector: u8, level: bool) {
+        let (idx, bitpos) = (vector as usize >> 6, vector as usize & 0x3f);
+        self.level_vectors[idx].set_bit(bitpos, level);
+    }
+
+    /// Move the interrupts accepted without holding this local APIC, i.e. those from other CPUs and those posted by
+    /// other vCPUs in the PIR (see 30.6 Posted-Interrupt Processing), to the IRR. Returns whether there was any.
+    ///
+    /// An INIT sent by another CPU is processed first, discarding the interrupts accepted before it.
+    pub fn sync_irr(&mut self) -> bool {
+        if self.bus.take_init(self.vapic_id) {
+            self.process_init();
+        }
+
+        let pir = self.bus.pi_desc(self.vapic_id).take_pir();
+        let (irr, tmr) = self.bus.take_remote(self.vapic_id);
+
+        let mut pending = false;
+        for i in 0..4 {
+            let mut bits = pir[i] | irr[i];
+            pending |= bits != 0;
+            while bits != 0 {
+                let bitpos = bits.trailing_zeros();
+                let level = tmr[i] & (1 << bitpos) != 0;
+                self.accept_intr((i as u32) << 6 | bitpos, level);
+                bits &= bits - 1;
+            }
+        }
+        pending
+    }
+
+    /// The value of MSR_KVM_PV_EOI_EN.
+    pub fn pv_eoi_msr(&self) -> u64 {
+        self.pv_eoi.msr()
+    }
+
+    /// Write MSR_KVM_PV_EOI_EN, enabling or disabling paravirtual EOI with the flag at the guest physical address it
+    /// holds. Fails if the reserved bit 1 is set, or the flag is not in guest memory.
+    pub fn write_pv_eoi_msr(&mut self, value: u64) -> AxResult {
+        self.sync_pv_eoi_from_guest();
+        let flag = self.pv_eoi_flag(value)?;
+        self.pv_eoi.set_msr(value, flag);
+        Ok(())
+    }
+
+    /// Check the value of MSR_KVM_PV_EOI_EN, and map the flag it points to if it enables paravirtual EOI.
+    fn pv_eoi_flag(&self, value: u64) -> AxResult<Option<HostVirtAddr>> {
+        if !PvEoi::is_enabled_by(value) {
+            return Ok(None);
+        }
+        if value & !PV_EOI_MSR_ADDR_MASK & !1 != 0 {
+            warn!("[VLAPIC] write MSR_KVM_PV_EOI_EN: reserved bit set in {value:#x}");
+            return Err(AxError::InvalidInput);
+        }
+        self.bus
+            .translate_gpa(GuestPhysAddr::from_usize(
+                (value & PV_EOI_MSR_ADDR_MASK) as usize,
+            ))
+            .map(Some)
+    }
+
+    /// Perform the EOI the guest signalled by clearing the paravirtual EOI flag, if it did since the last VM entry.
+    pub fn sync_pv_eoi_from_guest(&mut self) {
+        if self.pv_eoi.take_pending() {
+            debug!("[VLAPIC] implicit EOI of vector {:#x}", self.isrv);
+            self.process_eoi();
+        }
+    }
+
+    /// Set the paravirtual EOI flag before a VM entry if the guest may skip the EOI of the interrupt in service.
+    ///
+    /// The EOI must not have side effects, i.e. the vector is not in the EOI-exit bitmap, and no other interrupt
+    /// may be pending in the IRR, as the EOI would make it deliverable without a VM exit.
+    pub fn sync_pv_eoi_to_guest(&mut self) {
+        self.sync_pv_eoi_from_guest();
+        let vector = self.isrv as usize;
+        if !self.pv_eoi.is_enabled() || vector == 0 || self.find_irrv() != 0 {
+            return;
+        }
+        if self.eoi_exit_bitmap()[vector >> 6] & (1 << (vector & 0x3f)) != 0 {
+            return;
+        }
+        self.pv_eoi.set_pending();
+    }
+
+    /// Use posted interrupts for fixed interrupts from other vCPUs, see [`ApicBus::deliver_fixed`].
+    pub fn enable_posted_interrupts(&mut self, nv: u8, ndst: u32) -> AxResult {
+        self.bus.enable_posted(self.vapic_id, nv, ndst)
+    }
+
+    /// Stop using posted interrupts, moving those already posted to the IRR.
+    pub fn disable_posted_interrupts(&mut self) {
+        self.bus.disable_posted(self.vapic_id);
+        self.sync_irr();
+    }
+
+    /// Posted-interrupt descriptor address (64 bits).
+    pub fn posted_interrupt_desc_addr(&self) -> HostPhysAddr {
+        memory::virt_to_phys(HostVirtAddr::from_usize(
+            self.bus.pi_desc(self.vapic_id) as *const _ as usize,
+        ))
+    }
+
+    /// Update the notification destination of posted interrupts, e.g. after the vCPU migrated.
+    pub fn set_posted_interrupt_dest(&mut self, ndst: u32) {
+        self.bus.pi_desc(self.vapic_id).set_ndst(ndst);
+    }
+
+    /// Suppress notifications of posted interrupts, e.g. while the vCPU is not running.
+    pub fn suppress_posted_interrupt_notification(&mut self, suppress: bool) {
+        self.bus.pi_desc(self.vapic_id).set_sn(suppress);
+    }
+
+    /// Use the virtual-APIC page as the AVIC backing page, and enter it in the physical APIC ID table of the bus.
+    pub fn enable_avic(&mut self) -> AxResult {
+        self.bus
+            .add_avic_dest(self.vapic_id, self.virtual_apic_page_addr())
+    }
+
+    /// Remove the local APIC from the APIC ID tables, going back to interrupts delivered by the VMM.
+    pub fn disable_avic(&mut self) {
+        self.bus.remove_avic_dest(self.vapic_id);
+    }
+
+    /// Set or clear the IsRunning bit of the physical APIC ID table entry, as the vCPU is loaded on the CPU with
+    /// physical APIC ID `host_apic_id` or put.
+    pub fn set_avic_running(&mut self, host_apic_id: Option<u32>) {
+        self.bus.set_avic_running(self.vapic_id, host_apic_id);
+    }
+
+    /// Handle an AVIC_INCOMPLETE_IPI #VMEXIT, with the ICR in EXITINFO1 and the cause in EXITINFO2.
+    ///
+    /// IPIs the processor does not deliver, i.e. those that are not fixed and edge-triggered, are sent as for a write
+    /// to ICR_LO: NMIs and INITs are delivered, while SIPIs and other unsupported delivery modes are dropped with a
+    /// warning. For targets that are not running, the processor has set the IRR of their backing pages, and they are
+    /// woken up with [`ApicBus::kick_avic`].
+    pub fn handle_avic_incomplete_ipi(&mut self, exit_info1: u64, exit_info2: u64) -> AxResult {
+        if self.is_x2apic_enabled() {
+            return ax_err!(BadState, "AVIC does not virtualize the x2APIC mode");
+        }
+        let Some(cause) = IncompleteIpiCause::from_exit_info2(exit_info2) else {
+            warn!("[VLAPIC] unknown AVIC incomplete IPI cause {exit_info2:#x}");
+            return Err(AxError::InvalidData);
+        };
+        self.sync_avic_isrv();
+
+        let icr_hi = (exit_info1 >> 32) as u32;
+        let icr_low = InterruptCommandRegisterLowLocal::new(exit_info1 as u32);
+        match cause {
+            IncompleteIpiCause::InvalidIntType => {
+                self.handle_write(ApicRegOffset::ICRHi, icr_hi as _, AccessWidth::Dword)?;
+                self.handle_write(
+                    ApicRegOffset::ICRLow,
+                    icr_low.get() as _,
+                    AccessWidth::Dword,
+                )
+            }
+            IncompleteIpiCause::TargetNotRunning => {
+                use crate::consts::xapic::XAPIC_BROADCAST_DEST_ID;
+
+                let dest = INTERRUPT_COMMAND_HIGH::Destination.read(icr_hi);
+                let shorthand = icr_low
+                    .read_as_enum::<APICDestination>(INTERRUPT_COMMAND_LOW::DestinationShorthand)
+                    .ok_or(AxError::InvalidData)?;
+                let dmask = self.calculate_dest(
+                    shorthand,
+                    dest == XAPIC_BROADCAST_DEST_ID,
+                    dest,
+                    icr_low.matches_all(INTERRUPT_COMMAND_LOW::DestinationMode::Physical),
+                    false,
+                )?;
+                let vec = icr_low.read(INTERRUPT_COMMAND_LOW::Vector) as u8;
+                for i in dmask.iter().filter(|&i| i != self.vapic_id as usize) {
+                    self.bus.kick_avic(i, vec);
                 }
+                Ok(())
+            }
+            IncompleteIpiCause::InvalidTarget => {
+                // As on real hardware, IPIs to APIC IDs without a local APIC are lost.
+                debug!("[VLAPIC] AVIC IPI {exit_info1:#x} to an invalid target ignored");
+                Ok(())
+            }
+            IncompleteIpiCause::InvalidBackingPage => {
+                ax_err!(
+                    BadState,
+                    "AVIC IPI to a target with an invalid backing page"
+                )
             }
         }
+    }
 
-        Ok(dmask)
+    /// Handle an AVIC_NOACCEL #VMEXIT with EXITINFO1. Returns whether it was handled.
+    ///
+    /// Writes that trap have been done to the backing page, and their side effects are performed here as for an
+    /// emulated write of the same value. Other accesses fault before completing, and are not handled: the VMM should
+    /// emulate the instruction through [`Self::handle_read`] or [`Self::handle_write`].
+    pub fn handle_avic_unaccelerated_access(&mut self, exit_info1: u64) -> AxResult<bool> {
+        let (offset, write) = decode_noaccel(exit_info1);
+        if !write || !is_noaccel_trap(offset) {
+            return Ok(false);
+        }
+        self.sync_avic_isrv();
+
+        let index = offset >> 4;
+        let reg = xapic_mmio_access_reg_offset(GuestPhysAddr::from_usize(offset));
+        let val = self.page_slot(index);
+        self.handle_write(reg, val as _, AccessWidth::Dword)?;
+        if reg == ApicRegOffset::ID {
+            // The APIC ID is read-only, undo the write of the processor.
+            self.set_page_slot(index, self.vapic_id << 24);
+        }
+        Ok(true)
+    }
+
+    /// The processor dispatches interrupts and accelerates EOIs of edge-triggered ones on the backing page, so the
+    /// in-service vector is taken from the ISR before handling AVIC #VMEXITs.
+    fn sync_avic_isrv(&mut self) {
+        self.isrv = self.find_isrv();
+    }
+
+    /// The vector of the highest priority interrupt in the IRR, if its priority class is above the PPR.
+    ///
+    /// 11.8.3.1 Task and Processor Priorities
+    /// The processor will service only those interrupts that have a priority higher than that specified in the PPR.
+    pub fn pending_intr(&self) -> Option<u8> {
+        let vector = self.find_irrv();
+        if vector == 0 || prio(vector) <= prio(self.regs().PPR.get()) {
+            return None;
+        }
+        Some(vector as u8)
+    }
+
+    /// Acknowledge the interrupt returned by [`Self::pending_intr`], as the processor does when it dispatches it.
+    ///
+    /// 11.8.4 Interrupt Acceptance for Fixed Interrupts
+    /// The local APIC clears the highest priority IRR bit, sets the corresponding ISR bit and updates the PPR.
+    ///
+    /// 11.9 Spurious Interrupt
+    /// If the interrupt has been masked by a raise of the task priority since it was reported pending, the spurious
+    /// vector from the SVR is returned instead, and neither the IRR nor the ISR is modified.
+    pub fn acknowledge_intr(&mut self) -> Option<u8> {
+        let vector = self.find_irrv();
+        if vector == 0 {
+            return None;
+        }
+
+        if prio(vector) <= prio(self.regs().PPR.get()) {
+            let spurious = self
+                .svr_last
+                .read(SPURIOUS_INTERRUPT_VECTOR::SPURIOUS_VECTOR);
+            debug!(
+                "[VLAPIC] interrupt {vector:#x} masked by PPR, deliver spurious vector {spurious:#x}"
+            );
+            return Some(spurious as u8);
+        }
+
+        let (idx, bitpos) = extract_index_and_bitpos_u32(vector);
+
+        self.irr(idx).fetch_and(!(1 << bitpos), Ordering::SeqCst);
+
+        let mut isr = self.regs().ISR[idx].get() as u32;
+        isr.set_bit(bitpos, true);
+        self.regs().ISR[idx].set(isr as _);
+
+        self.isrv = self.find_isrv();
+        self.update_ppr();
+
+        Some(vector as u8)
+    }
+
+    /// 11.5.3 Error Handling
+    /// Record an error in the pending ESR, and signal it through the LVT Error Register if it's not masked.
+    fn set_err(&mut self, mask: ErrorStatusRegisterValue) {
+        self.esr_pending.modify(mask);
+
+        // Avoid recursion if the LVT Error Register itself holds an illegal vector.
+        if self.esr_firing == 0 {
+            self.esr_firing = 1;
+            let lvt = self.lvt_last.lvt_err.get();
+            if (lvt & APIC_LVT_M) == 0 {
+                let vec = lvt & APIC_LVT_VECTOR;
+                if vec >= 16 {
+                    self.accept_intr(vec, LAPIC_TRIG_EDGE);
+                }
+            }
+            self.esr_firing = 0;
+        }
     }
 
     fn calculate_dest(
@@ -324,56 +918,375 @@ impl VirtualApicRegs {
         dest: u32,
         is_phys: bool,
         lowprio: bool,
-    ) -> AxResult<u64> {
-        let mut dmask = 0;
+    ) -> AxResult<VCpuSet> {
+        let vcpu_num = self.bus.vcpu_num();
+        let mut dmask = VCpuSet::new(vcpu_num);
         match shorthand {
             APICDestination::NoShorthand => {
-                dmask = self.calculate_dest_no_shorthand(is_broadcast, dest, is_phys, lowprio)?;
+                dmask = self
+                    .bus
+                    .calculate_dest(is_broadcast, dest, is_phys, lowprio)?;
             }
             APICDestination::SELF => {
-                dmask.set_bit(self.vapic_id as usize, true);
+                dmask.insert(self.vapic_id as usize);
             }
             APICDestination::AllIncludingSelf => {
-                dmask = vmm::current_vm_active_vcpus() as u64;
+                dmask = VCpuSet::full(vcpu_num);
             }
             APICDestination::AllExcludingSelf => {
-                dmask = vmm::current_vm_active_vcpus() as u64;
-                dmask &= !(1 << self.vapic_id);
+                dmask = VCpuSet::full(vcpu_num);
+                dmask.remove(self.vapic_id as usize);
             }
         }
 
         Ok(dmask)
     }
 
-    fn handle_self_ipi(&mut self) {
-        unimplemented!("x2apic handle_self_ipi");
+    /// 11.12.11 SELF IPI Register
+    /// A write to the SELF IPI register sends a fixed, edge-triggered IPI to this local APIC.
+    fn handle_self_ipi(&mut self, vector: u32) {
+        if vector < 16 {
+            self.set_err(ERROR_STATUS::SendIllegalVector::SET);
+            debug!("[VLAPIC] Ignoring invalid self IPI {vector:#010X}");
+            return;
+        }
+        self.accept_intr(vector, LAPIC_TRIG_EDGE);
+    }
+
+    fn set_intr(&mut self, vcpu_id: u32, vector: u32, level: bool) {
+        if vcpu_id == self.vapic_id {
+            self.accept_intr(vector, level);
+        } else {
+            // The target accepts the interrupt with `EmulatedLocalApic::accept_interrupt`, or from its PIR,
+            // which honours its own software-enable state.
+            self.bus.deliver_fixed(vcpu_id as _, vector as u8, level);
+        }
+    }
+
+    /// Deliver an NMI to `vcpu_id`, which the VMM injects as an NMI rather than an external interrupt.
+    fn inject_nmi(&mut self, vcpu_id: u32) {
+        vmm::inject_interrupt(self.bus.vm_id(), vcpu_id as _, NMI_VECTOR);
+    }
+
+    /// 11.5.1 Local Vector Table
+    /// Drive the LINT0 or LINT1 pin to the electrical `level` (high if `true`), and deliver the interrupt programmed
+    /// in its LVT entry when the pin becomes asserted according to its polarity.
+    ///
+    /// Fixed interrupts are delivered on the asserting edge, or, if level-triggered, whenever the pin is asserted
+    /// while the Remote IRR flag is clear. NMI, SMI and INIT are always edge-triggered. ExtINT is level-triggered
+    /// and not delivered here: it stays pending in [`Self::pending_extint`] as long as the pin is asserted.
+    pub fn set_lint(&mut self, pin: LintPin, level: bool) {
+        let polarity = self
+            .lint_lvt(pin)
+            .is_set(LVT_LINT0::InterruptInputPinPolarity);
+
+        let asserted = level ^ polarity;
+        let edge = asserted && !self.lint_asserted[pin as usize];
+        self.lint_asserted[pin as usize] = asserted;
+
+        self.deliver_lint(pin, edge);
+    }
+
+    /// The LVT entry of `pin`. LINT0 and LINT1 registers share the same layout.
+    fn lint_lvt(&self, pin: LintPin) -> LvtLint0RegisterLocal {
+        LvtLint0RegisterLocal::new(match pin {
+            LintPin::Lint0 => self.lvt_last.lvt_lint0.get(),
+            LintPin::Lint1 => self.lvt_last.lvt_lint1.get(),
+        })
+    }
+
+    /// Deliver the interrupt programmed in the LVT entry of `pin` if the pin is asserted. `edge` tells whether the
+    /// pin has just become asserted, which edge-triggered modes require.
+    fn deliver_lint(&mut self, pin: LintPin, edge: bool) {
+        let lvt = self.lint_lvt(pin);
+
+        if !self.lint_asserted[pin as usize] || lvt.is_set(LVT_LINT0::Mask) {
+            return;
+        }
+
+        match lvt.read_as_enum(LVT_LINT0::DeliveryMode) {
+            Some(LVT_LINT0::DeliveryMode::Value::Fixed) => {
+                let vector = lvt.read(LVT_LINT0::Vector);
+                // Level-sensitive interrupts are not supported for LINT1.
+                if pin == LintPin::Lint0 && lvt.is_set(LVT_LINT0::TriggerMode) {
+                    // Remote IRR is set once the interrupt is accepted, and reset by the EOI for its vector.
+                    if !lvt.is_set(LVT_LINT0::RemoteIRR)
+                        && self.accept_intr(vector, LAPIC_TRIG_LEVEL)
+                    {
+                        self.set_lint0_remote_irr(true);
+                    }
+                } else if edge {
+                    self.accept_intr(vector, LAPIC_TRIG_EDGE);
+                }
+            }
+            Some(LVT_LINT0::DeliveryMode::Value::NMI) if edge => {
+                debug!("[VLAPIC] {pin:?} delivers NMI to vcpu {}", self.vapic_id);
+                self.inject_nmi(self.vapic_id);
+            }
+            Some(LVT_LINT0::DeliveryMode::Value::SMI) if edge => {
+                warn!("[VLAPIC] SMI through {pin:?} do not support");
+            }
+            Some(LVT_LINT0::DeliveryMode::Value::INIT) if edge => {
+                debug!("[VLAPIC] {pin:?} delivers INIT to vcpu {}", self.vapic_id);
+                self.bus.post_init(self.vapic_id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Update the Remote IRR flag of the LVT LINT0 register, in both the local copy and the virtual-APIC page.
+    fn set_lint0_remote_irr(&mut self, set: bool) {
+        let field = if set {
+            LVT_LINT0::RemoteIRR::SET
+        } else {
+            LVT_LINT0::RemoteIRR::CLEAR
+        };
+        self.lvt_last.lvt_lint0.modify(field);
+        self.regs().LVT_LINT0.modify(field);
+    }
+
+    /// 11.5.1 Local Vector Table
+    /// Reset the Remote IRR flag of LINT0 when the EOI for its level-triggered fixed interrupt is received, and
+    /// deliver the interrupt again if the pin is still asserted.
+    fn lint0_eoi(&mut self, vector: u32) {
+        let lvt = self.lint_lvt(LintPin::Lint0);
+        if !lvt.is_set(LVT_LINT0::RemoteIRR) || lvt.read(LVT_LINT0::Vector) != vector {
+            return;
+        }
+
+        self.set_lint0_remote_irr(false);
+        self.deliver_lint(LintPin::Lint0, false);
     }
 
-    fn set_intr(&mut self, vcpu_id: u32, vector: u32, level: bool) {
-        unimplemented!(
-            "set_intr, vcpu_id: {}, vector: {}, level: {}",
-            vcpu_id,
-            vector,
-            level
-        );
-    }
+    /// Returns whether a LINT pin programmed with the ExtINT delivery mode is asserted and not masked, in which case
+    /// the interrupt vector is supplied by the external 8259 PIC.
+    pub fn pending_extint(&self) -> bool {
+        [
+            (LintPin::Lint0, self.lvt_last.lvt_lint0.get()),
+            (LintPin::Lint1, self.lvt_last.lvt_lint1.get()),
+        ]
+        .into_iter()
+        .any(|(pin, lvt)| {
+            let lvt = LvtLint0RegisterLocal::new(lvt);
+            self.lint_asserted[pin as usize]
+                && !lvt.is_set(LVT_LINT0::Mask)
+                && lvt.matches_all(LVT_LINT0::DeliveryMode::ExtINT)
+        })
+    }
+
+    /// 11.6.1 Interrupt Command Register (ICR)
+    /// Send an INIT or a Start-Up IPI to `vcpu_id`.
+    ///
+    /// An INIT resets the local APIC of the target with [`Self::reset_init`] when it next syncs its IRR, and the VMM
+    /// learns about it with [`Self::take_init`]. The INIT level de-assert message, which only synchronizes
+    /// arbitration IDs, is ignored. Start-Up IPIs are not emulated and dropped.
+    fn process_init_sipi(
+        &mut self,
+        vcpu_id: u32,
+        mode: APICDeliveryMode,
+        icr_low: InterruptCommandRegisterLowLocal,
+    ) {
+        match mode {
+            APICDeliveryMode::INIT
+                if icr_low.matches_all(
+                    INTERRUPT_COMMAND_LOW::Level::DeAssert
+                        + INTERRUPT_COMMAND_LOW::TriggerMode::Level,
+                ) =>
+            {
+                debug!("[VLAPIC] INIT level de-assert to vcpu {vcpu_id} ignored");
+            }
+            APICDeliveryMode::INIT => {
+                debug!("[VLAPIC] sending INIT to vcpu {vcpu_id}");
+                self.bus.post_init(vcpu_id);
+            }
+            _ => {
+                warn!(
+                    "[VLAPIC] {mode:?} IPI {:#010X} to vcpu {vcpu_id} do not support, dropped",
+                    icr_low.get()
+                );
+            }
+        }
+    }
+
+    /// 11.4.7.3 Local APIC State After an INIT Reset ("Wait-for-SIPI" State)
+    /// Reset the local APIC on receipt of an INIT, and record it for the VMM.
+    fn process_init(&mut self) {
+        debug!("[VLAPIC] vlapic [{}] received INIT", self.vapic_id);
+        if let Err(err) = self.reset_init() {
+            warn!("[VLAPIC] INIT reset failed: {err:?}");
+        }
+        self.init_received = true;
+    }
+
+    /// Returns whether the local APIC was reset by an INIT since the last call, in which case the vCPU should enter
+    /// the wait-for-SIPI state.
+    pub fn take_init(&mut self) -> bool {
+        core::mem::take(&mut self.init_received)
+    }
+
+    /// Publish the registers selecting the interrupt messages this local APIC accepts to the APIC bus.
+    fn publish_dest(&self) {
+        self.bus.update_dest(
+            self.vapic_id,
+            self.ldr(),
+            self.regs().DFR.get(),
+            self.is_x2apic_enabled(),
+        );
+    }
+
+    /// The Logical Destination Register, derived from the APIC ID in x2APIC mode.
+    fn ldr(&self) -> u32 {
+        if self.is_x2apic_enabled() {
+            x2apic_ldr(self.vapic_id)
+        } else {
+            self.regs().LDR.get()
+        }
+    }
+
+    /// The ID register holding the APIC ID in the format of the mode selected by `apic_base`: in bits 31:0 in
+    /// x2APIC mode, and in bits 31:24 otherwise.
+    fn id_reg(&self, apic_base: ApicBaseRegisterMsr) -> u32 {
+        if apic_base.is_set(APIC_BASE::XAPIC_ENABLED) && apic_base.is_set(APIC_BASE::X2APIC_Enabled)
+        {
+            self.vapic_id
+        } else {
+            self.vapic_id << 24
+        }
+    }
+
+    /// The low 32 bits of the register slot `index`, i.e. the register offset divided by 16, of the virtual-APIC page.
+    fn page_slot(&self, index: usize) -> u32 {
+        debug_assert!(index < LAPIC_REG_SLOTS + LAPIC_EXT_REG_SLOTS);
+        unsafe {
+            self.virtual_lapic
+                .cast::<u32>()
+                .add(index * 4)
+                .read_volatile()
+        }
+    }
+
+    /// The IRR register `idx` of the virtual-APIC page, in which other CPUs set bits directly when it's the AVIC
+    /// backing page, see [`ApicBus::deliver_fixed`].
+    fn irr(&self, idx: usize) -> &AtomicU32 {
+        // SAFETY: the IRR registers are 32-bit aligned values on the page owned by `apic_page`.
+        unsafe {
+            AtomicU32::from_ptr(
+                self.virtual_lapic
+                    .cast::<u32>()
+                    .as_ptr()
+                    .add((0x20 + idx) * 4),
+            )
+        }
+    }
+
+    fn set_page_slot(&mut self, index: usize, val: u32) {
+        debug_assert!(index < LAPIC_REG_SLOTS + LAPIC_EXT_REG_SLOTS);
+        unsafe {
+            self.virtual_lapic
+                .cast::<u32>()
+                .add(index * 4)
+                .write_volatile(val)
+        }
+    }
+
+    /// Save the state of the local APIC, see [`LapicState`].
+    ///
+    /// Interrupts accepted from other CPUs are expected to be moved to the IRR with [`Self::sync_irr`] first.
+    pub fn save(&self) -> LapicState {
+        let lvt = &self.lvt_last;
+        LapicState {
+            version: LAPIC_STATE_VERSION,
+            apic_base: self.apic_base.get(),
+            regs: core::array::from_fn(|index| self.page_slot(index)),
+            ext_regs: core::array::from_fn(|index| self.page_slot(LAPIC_REG_SLOTS + index)),
+            svr: self.svr_last.get(),
+            lvt: [
+                lvt.lvt_cmci.get(),
+                lvt.lvt_timer.get(),
+                lvt.lvt_thermal.get(),
+                lvt.lvt_perf_count.get(),
+                lvt.lvt_lint0.get(),
+                lvt.lvt_lint1.get(),
+                lvt.lvt_err.get(),
+            ],
+            esr_pending: self.esr_pending.get(),
+            isrv: self.isrv,
+            lint_asserted: self.lint_asserted,
+            level_vectors: self.level_vectors,
+            timer: self.virtual_timer.save(),
+            pv_eoi_msr: self.pv_eoi.msr(),
+        }
+    }
+
+    /// Restore the state of the local APIC saved by [`Self::save`], possibly on another host.
+    ///
+    /// The APIC timer is re-armed for the time that was left when the state was saved, and the destination and the
+    /// wire mode of the APIC bus are updated from the restored registers.
+    ///
+    /// Fails with `InvalidInput`, leaving the local APIC untouched, if the ID register in the state does not hold the
+    /// APIC ID of this local APIC, or if the saved MSR_KVM_PV_EOI_EN is invalid.
+    pub fn restore(&mut self, state: &LapicState) -> AxResult {
+        const ID_SLOT: usize = 0x20 >> 4;
+
+        if state.version != LAPIC_STATE_VERSION {
+            return ax_err!(
+                Unsupported,
+                "local APIC state version does not match LAPIC_STATE_VERSION"
+            );
+        }
+        if state.regs[ID_SLOT] != self.id_reg(ApicBaseRegisterMsr::new(state.apic_base)) {
+            return ax_err!(InvalidInput, "local APIC state has another APIC ID");
+        }
+        let pv_eoi_flag = self.pv_eoi_flag(state.pv_eoi_msr)?;
+
+        // A paravirtual EOI pending for the replaced ISR is dropped without performing it.
+        self.pv_eoi.take_pending();
+        self.pv_eoi = PvEoi::default();
 
-    fn inject_nmi(&mut self, vcpu_id: u32) {
-        unimplemented!("inject_nmi vcpu_id: {}", vcpu_id);
-    }
+        for (index, &val) in state.regs.iter().enumerate() {
+            self.set_page_slot(index, val);
+        }
+        for (index, &val) in state.ext_regs.iter().enumerate() {
+            self.set_page_slot(LAPIC_REG_SLOTS + index, val);
+        }
+        // The extended registers follow the profile of this local APIC, which may differ from the saved one.
+        self.regs().EXT_FEATURE.set(self.ext_feature_reg());
+        self.regs()
+            .EXT_CONTROL
+            .set(self.regs().EXT_CONTROL.get() & self.ext_control_writable());
+        self.apic_base.set(state.apic_base);
+        self.svr_last.set(state.svr);
+
+        let [cmci, timer, thermal, perf_count, lint0, lint1, err] = state.lvt;
+        self.lvt_last.lvt_cmci.set(cmci);
+        self.lvt_last.lvt_timer.set(timer);
+        self.lvt_last.lvt_thermal.set(thermal);
+        self.lvt_last.lvt_perf_count.set(perf_count);
+        self.lvt_last.lvt_lint0.set(lint0);
+        self.lvt_last.lvt_lint1.set(lint1);
+        self.lvt_last.lvt_err.set(err);
+
+        self.esr_pending.set(state.esr_pending);
+        self.esr_firing = 0;
+        self.isrv = state.isrv;
+        self.lint_asserted = state.lint_asserted;
+        self.level_vectors = state.level_vectors;
+        self.virtual_timer.restore(&state.timer)?;
+
+        self.publish_dest();
+        // An unmasked LINT0 in ExtINT mode receives the PIC output, as after the guest wrote it.
+        let lint0 = self.lint_lvt(LintPin::Lint0);
+        if !lint0.is_set(LVT_LINT0::Mask)
+            && lint0.matches_all(LVT_LINT0::DeliveryMode::ExtINT)
+            && self
+                .bus
+                .switch_wire_mode(&[WireMode::Intr, WireMode::Null], WireMode::Lapic)
+        {
+            debug!("[VLAPIC] vpic wire mode changed to LAPIC");
+        }
+        self.pv_eoi.set_msr(state.pv_eoi_msr, pv_eoi_flag);
 
-    fn process_init_sipi(
-        &mut self,
-        vcpu_id: u32,
-        mode: APICDeliveryMode,
-        icr_low: InterruptCommandRegisterLowLocal,
-    ) {
-        unimplemented!(
-            "process_init_sipi, vcpu_id: {}, mode: {:?} icr_low: {:#010X}",
-            vcpu_id,
-            mode,
-            icr_low.get()
-        );
+        Ok(())
     }
 
     /// Figure 11-13. Logical Destination Register (LDR)
@@ -385,6 +1298,7 @@ impl VirtualApicRegs {
         ldr &= !LDR_RESERVED;
 
         self.regs().LDR.set(ldr);
+        self.publish_dest();
         debug!("[VLAPIC] apic_id={apic_id:#010X} write LDR register to {ldr:#010X}");
     }
 
@@ -398,6 +1312,7 @@ impl VirtualApicRegs {
         dfr &= APIC_DFR_MODEL_MASK;
         dfr |= APIC_DFR_RESERVED;
         self.regs().DFR.set(dfr);
+        self.publish_dest();
 
         debug!("[VLAPIC] write DFR register to {dfr:#010X}");
 
@@ -417,9 +1332,22 @@ impl VirtualApicRegs {
     /// Figure 11-14. Spurious-Interrupt Vector Register (SVR)
     /// Handle writes to the SVR register.
     fn write_svr(&mut self) -> AxResult {
-        let new = self.regs().SVR.extract();
+        let mut new = self.regs().SVR.extract();
         let old = self.svr_last;
 
+        // 11.8.5: the Suppress EOI Broadcasts bit is reserved without EOI-broadcast suppression.
+        if !self.profile.directed_eoi
+            && new.is_set(SPURIOUS_INTERRUPT_VECTOR::EOIBroadcastSuppression)
+        {
+            if self.is_x2apic_enabled() {
+                warn!("[VLAPIC] write SVR register: EOI-broadcast suppression is not supported");
+                self.regs().SVR.set(old.get());
+                return Err(AxError::InvalidInput);
+            }
+            new.modify(SPURIOUS_INTERRUPT_VECTOR::EOIBroadcastSuppression::CLEAR);
+            self.regs().SVR.set(new.get());
+        }
+
         self.svr_last = new;
 
         if old.is_set(SPURIOUS_INTERRUPT_VECTOR::APICSoftwareEnableDisable)
@@ -428,9 +1356,15 @@ impl VirtualApicRegs {
             debug!("[VLAPIC] vlapic [{}] is software-disabled", self.vapic_id);
             // The apic is now disabled so stop the apic timer
             // and mask all the LVT entries.
-            self.virtual_timer.stop_timer()?;
+            // Interrupts already in the IRR and ISR are held (SDM 11.4.7.2).
+            if self.virtual_timer.is_started() {
+                self.virtual_timer.stop_timer()?;
+            }
             self.mask_lvts()?;
-            warn!("VM wire mode should be changed to INTR here, unimplemented");
+            // The only local APIC with LINT0 in ExtINT mode is disabled, hand the PIC output back to INTR.
+            if self.bus.switch_wire_mode(&[WireMode::Null], WireMode::Intr) {
+                debug!("[VLAPIC] vpic wire mode changed to INTR");
+            }
         } else if !old.is_set(SPURIOUS_INTERRUPT_VECTOR::APICSoftwareEnableDisable)
             && new.is_set(SPURIOUS_INTERRUPT_VECTOR::APICSoftwareEnableDisable)
         {
@@ -438,9 +1372,11 @@ impl VirtualApicRegs {
 
             // The apic is now enabled so restart the apic timer
             // if it is configured in periodic mode.
-            if self.virtual_timer.is_periodic() {
+            // The LVT Timer Register is still masked until the guest unmasks it.
+            if self.virtual_timer.is_periodic() && self.virtual_timer.read_icr() > 0 {
                 debug!("Restarting the apic timer");
-                self.virtual_timer.restart_timer()?;
+                self.virtual_timer
+                    .write_icr(self.virtual_timer.read_icr())?;
             }
         }
 
@@ -455,9 +1391,12 @@ impl VirtualApicRegs {
     }
 
     fn write_icr(&mut self) -> AxResult {
-        self.regs()
-            .ICR_LO
-            .modify(INTERRUPT_COMMAND_LOW::DeliveryStatus::Idle);
+        // 11.6.1: the IPI is sent before the write completes, so the Delivery Status reads idle. The Remote Read
+        // Status field is only made valid by a remote read.
+        self.regs().ICR_LO.modify(
+            INTERRUPT_COMMAND_LOW::DeliveryStatus::Idle
+                + INTERRUPT_COMMAND_LOW::RemoteReadStatus::Invalid,
+        );
 
         let icr_low = self.regs().ICR_LO.extract();
 
@@ -475,12 +1414,21 @@ impl VirtualApicRegs {
         let mode = icr_low
             .read_as_enum::<APICDeliveryMode>(INTERRUPT_COMMAND_LOW::DeliveryMode)
             .ok_or(AxError::InvalidData)?;
-        let is_phys = icr_low.is_set(INTERRUPT_COMMAND_LOW::DestinationMode);
+        let is_phys = icr_low.matches_all(INTERRUPT_COMMAND_LOW::DestinationMode::Physical);
         let shorthand = icr_low
             .read_as_enum::<APICDestination>(INTERRUPT_COMMAND_LOW::DestinationShorthand)
             .ok_or(AxError::InvalidData)?;
 
-        if mode == APICDeliveryMode::Fixed && vec < 16 {
+        if mode == APICDeliveryMode::RemoteRead {
+            return self.remote_read(vec, shorthand, dest, is_broadcast, is_phys);
+        }
+
+        // The trigger mode only applies to INIT level de-assert on recent processors (11.6.1), but is honoured for
+        // fixed and lowest priority IPIs as in KVM, setting the TMR of the targets.
+        let level = icr_low.matches_all(INTERRUPT_COMMAND_LOW::TriggerMode::Level);
+        let lowprio = mode == APICDeliveryMode::LowestPriority;
+
+        if (mode == APICDeliveryMode::Fixed || lowprio) && vec < 16 {
             self.set_err(ERROR_STATUS::SendIllegalVector::SET);
             debug!("[VLAPIC] Ignoring invalid IPI {vec:#010X}");
         } else if (shorthand == APICDestination::SELF
@@ -497,29 +1445,26 @@ impl VirtualApicRegs {
                 self.regs().ICR_HI.get(),
                 vec
             );
-            let dmask = self.calculate_dest(shorthand, is_broadcast, dest, is_phys, false)?;
-
-            // TODO: we need to get the specific vcpu number somehow.
-            for i in 0..vmm::current_vm_vcpu_num() as u32 {
-                if dmask & (1 << i) != 0 {
-                    match mode {
-                        APICDeliveryMode::Fixed => {
-                            self.set_intr(i, vec, LAPIC_TRIG_EDGE);
-                            debug!("[VLAPIC] sending IPI {vec} to vcpu {i}");
-                        }
-                        APICDeliveryMode::NMI => {
-                            self.inject_nmi(i);
-                            debug!("[VLAPIC] sending NMI to vcpu {i}");
-                        }
-                        APICDeliveryMode::INIT | APICDeliveryMode::StartUp => {
-                            self.process_init_sipi(i, mode, icr_low);
-                        }
-                        APICDeliveryMode::SMI => {
-                            warn!("[VLPAIC] SMI IPI do not support");
-                        }
-                        _ => {
-                            error!("Unhandled icrlo write with mode {mode:?}\n");
-                        }
+            let dmask = self.calculate_dest(shorthand, is_broadcast, dest, is_phys, lowprio)?;
+
+            for i in dmask.iter().map(|i| i as u32) {
+                match mode {
+                    APICDeliveryMode::Fixed | APICDeliveryMode::LowestPriority => {
+                        self.set_intr(i, vec, level);
+                        debug!("[VLAPIC] sending IPI {vec} to vcpu {i}");
+                    }
+                    APICDeliveryMode::NMI => {
+                        self.inject_nmi(i);
+                        debug!("[VLAPIC] sending NMI to vcpu {i}");
+                    }
+                    APICDeliveryMode::INIT | APICDeliveryMode::StartUp => {
+                        self.process_init_sipi(i, mode, icr_low);
+                    }
+                    APICDeliveryMode::SMI => {
+                        warn!("[VLPAIC] SMI IPI do not support");
+                    }
+                    _ => {
+                        error!("Unhandled icrlo write with mode {mode:?}\n");
                     }
                 }
             }
@@ -528,6 +1473,81 @@ impl VirtualApicRegs {
         Ok(())
     }
 
+    /// The ICR is accessed with 32-bit MMIO in xAPIC mode, and as a 64-bit MSR in x2APIC mode.
+    fn check_icr_width(&self, width: AccessWidth) -> AxResult {
+        if self.is_x2apic_enabled() ^ (width == AccessWidth::Qword) {
+            warn!(
+                "[VLAPIC] Illegal access of ICR register at width {:?} with X2APIC {}",
+                width,
+                if self.is_x2apic_enabled() {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            );
+            return Err(AxError::InvalidInput);
+        }
+        Ok(())
+    }
+
+    /// 11.6.1 Interrupt Command Register (ICR)
+    /// Remote read IPI (delivery mode 011): read the register at offset `vector << 4` of the destination local APIC
+    /// into the Remote Read Register (RRD), and report in the Remote Read Status field of the ICR whether it
+    /// succeeded. The destination must be a single local APIC, selected without shorthand. Registers whose value is
+    /// computed on read, such as the Current Count Register, are read as stored on the virtual-APIC page.
+    ///
+    /// The delivery mode is reserved unless [`LapicProfile::remote_read`] is set, and always in x2APIC mode: the IPI
+    /// is not sent and the status stays invalid, or #GP is raised in x2APIC mode (11.12.1.3).
+    fn remote_read(
+        &mut self,
+        vector: u32,
+        shorthand: APICDestination,
+        dest: u32,
+        is_broadcast: bool,
+        is_phys: bool,
+    ) -> AxResult {
+        if self.is_x2apic_enabled() {
+            warn!("[VLAPIC] write ICR register: remote read is reserved in x2APIC mode");
+            return Err(AxError::InvalidInput);
+        }
+        if !self.profile.remote_read {
+            warn!("[VLAPIC] remote read IPI is not supported by the profile, ignored");
+            return Ok(());
+        }
+        if shorthand != APICDestination::NoShorthand || is_broadcast {
+            debug!("[VLAPIC] remote read IPI to more than one local APIC, invalid");
+            return Ok(());
+        }
+
+        let dmask = self.calculate_dest(shorthand, is_broadcast, dest, is_phys, false)?;
+        let mut targets = dmask.iter().map(|i| i as u32);
+        let (Some(target), None) = (targets.next(), targets.next()) else {
+            debug!("[VLAPIC] remote read IPI not to exactly one local APIC, invalid");
+            return Ok(());
+        };
+
+        let index = vector as usize;
+        let value = if target == self.vapic_id {
+            (index < LAPIC_REG_SLOTS).then(|| self.page_slot(index))
+        } else if index < LAPIC_REG_SLOTS {
+            self.bus.read_apic_page(target, index)
+        } else {
+            None
+        };
+        if let Some(value) = value {
+            debug!(
+                "[VLAPIC] remote read of register {:#05X} of vcpu {target}: {value:#010X}",
+                index << 4
+            );
+            self.regs().RRD.set(value);
+            self.regs()
+                .ICR_LO
+                .modify(INTERRUPT_COMMAND_LOW::RemoteReadStatus::Valid);
+        }
+
+        Ok(())
+    }
+
     fn extract_lvt_val(&self, offset: ApicRegOffset) -> u32 {
         match offset {
             ApicRegOffset::LvtCMCI => self.regs().LVT_CMCI.get(),
@@ -544,87 +1564,187 @@ impl VirtualApicRegs {
         }
     }
 
+    fn lvt_last_val(&self, offset: ApicRegOffset) -> u32 {
+        match offset {
+            ApicRegOffset::LvtCMCI => self.lvt_last.lvt_cmci.get(),
+            ApicRegOffset::LvtTimer => self.lvt_last.lvt_timer.get(),
+            ApicRegOffset::LvtThermal => self.lvt_last.lvt_thermal.get(),
+            ApicRegOffset::LvtPmc => self.lvt_last.lvt_perf_count.get(),
+            ApicRegOffset::LvtLint0 => self.lvt_last.lvt_lint0.get(),
+            ApicRegOffset::LvtLint1 => self.lvt_last.lvt_lint1.get(),
+            ApicRegOffset::LvtErr => self.lvt_last.lvt_err.get(),
+            _ => RESET_LVT_REG,
+        }
+    }
+
+    /// Figure 11-8. Local Vector Table (LVT)
+    /// Sanitize a value written to an LVT register according to the layout of that register.
+    ///
+    /// Reserved bits are dropped, and the read-only Delivery Status and Remote IRR bits keep their current value.
+    /// A reserved timer mode, or a delivery mode the register does not support (e.g. INIT or ExtINT in the CMCI,
+    /// thermal and performance counter entries), keeps the previous mode instead.
+    ///
+    /// 11.12.1.3 Reserved Bit Checking
+    /// In x2APIC mode, writing a reserved bit or a reserved encoding fails instead, which raises #GP in the guest.
+    fn sanitize_lvt(&self, offset: ApicRegOffset, val: u32) -> AxResult<u32> {
+        // Vector and Mask are present in every LVT register.
+        let mut writable = APIC_LVT_M | APIC_LVT_VECTOR;
+        let mut read_only = APIC_LVT_DS;
+        // The field holding the timer mode or the delivery mode, validated by `mode_valid`.
+        let mode_field;
+
+        let mode_valid = match offset {
+            ApicRegOffset::LvtTimer => {
+                mode_field = LVT_TIMER::TimerMode::SET.mask();
+                LVT_TIMER::TimerMode.read(val) != TimerMode::Reserved as u32
+            }
+            ApicRegOffset::LvtErr => {
+                mode_field = 0;
+                true
+            }
+            ApicRegOffset::LvtLint0 | ApicRegOffset::LvtLint1 => {
+                // LINT0 and LINT1 registers share the same layout.
+                writable |= LVT_LINT0::TriggerMode::SET.mask();
+                writable |= LVT_LINT0::InterruptInputPinPolarity::SET.mask();
+                read_only |= LVT_LINT0::RemoteIRR::SET.mask();
+                mode_field = LVT_LINT0::DeliveryMode::SET.mask();
+                matches!(
+                    LVT_LINT0::DeliveryMode.read_as_enum::<LVT_LINT0::DeliveryMode::Value>(val),
+                    Some(
+                        LVT_LINT0::DeliveryMode::Value::Fixed
+                            | LVT_LINT0::DeliveryMode::Value::SMI
+                            | LVT_LINT0::DeliveryMode::Value::NMI
+                            | LVT_LINT0::DeliveryMode::Value::INIT
+                            | LVT_LINT0::DeliveryMode::Value::ExtINT
+                    )
+                )
+            }
+            ApicRegOffset::LvtCMCI | ApicRegOffset::LvtThermal | ApicRegOffset::LvtPmc => {
+                // CMCI, thermal monitor and performance counter registers share the same layout.
+                mode_field = LVT_CMCI::DeliveryMode::SET.mask();
+                matches!(
+                    LVT_CMCI::DeliveryMode.read_as_enum::<LVT_CMCI::DeliveryMode::Value>(val),
+                    Some(
+                        LVT_CMCI::DeliveryMode::Value::Fixed
+                            | LVT_CMCI::DeliveryMode::Value::SMI
+                            | LVT_CMCI::DeliveryMode::Value::NMI
+                    )
+                )
+            }
+            _ => {
+                warn!("[VLAPIC] write unsupported APIC register: {offset:?}");
+                return Err(AxError::InvalidInput);
+            }
+        };
+        writable |= mode_field;
+
+        if self.is_x2apic_enabled() && (val & !(writable | read_only) != 0 || !mode_valid) {
+            warn!("[VLAPIC] write {offset} register: reserved bits or mode set in {val:#010X}");
+            return Err(AxError::InvalidInput);
+        }
+
+        let last = self.lvt_last_val(offset);
+        let mut new = (val & writable) | (last & read_only);
+        if !mode_valid {
+            warn!(
+                "[VLAPIC] write {offset} register: reserved mode in {val:#010X}, keep the previous one"
+            );
+            new = (new & !mode_field) | (last & mode_field);
+        }
+
+        Ok(new)
+    }
+
     fn write_lvt(&mut self, offset: ApicRegOffset) -> AxResult {
         let mut val = self.extract_lvt_val(offset);
 
-        if self
-            .regs()
-            .SVR
-            .is_set(SPURIOUS_INTERRUPT_VECTOR::APICSoftwareEnableDisable)
-        {
+        // 11.4.7.2: while the local APIC is software-disabled, attempts to clear the mask bits are ignored.
+        if !self.is_software_enabled() {
             val |= APIC_LVT_M;
         }
 
-        // Mask::Masked, Delivery Status:SendPending, Vector::SET(0xff)
-        let mut mask = APIC_LVT_M | APIC_LVT_DS | APIC_LVT_VECTOR;
+        let val = match self.sanitize_lvt(offset, val) {
+            Ok(val) => val,
+            Err(err) => {
+                // Roll back the virtual-APIC page to the last accepted value.
+                self.store_lvt(offset, self.lvt_last_val(offset))?;
+                return Err(err);
+            }
+        };
+
+        if offset == ApicRegOffset::LvtLint0 {
+            // vlapic mask/unmask LINT0 for ExtINT?
+            if (val & LVT_LINT0::DeliveryMode::SET.mask()) == LVT_LINT0::DeliveryMode::ExtINT.mask()
+            {
+                let last = self.lvt_last.lvt_lint0;
+                if last.is_set(LVT_LINT0::Mask) && val & LVT_LINT0::Mask::SET.mask() == 0 {
+                    // mask -> unmask: may from every vlapic in the vm
+                    if self
+                        .bus
+                        .switch_wire_mode(&[WireMode::Intr, WireMode::Null], WireMode::Lapic)
+                    {
+                        debug!("[VLAPIC] vpic wire mode changed to LAPIC");
+                    } else {
+                        warn!("[VLAPIC] invalid vpic wire mode change");
+                    }
+                } else if !last.is_set(LVT_LINT0::Mask) && val & LVT_LINT0::Mask::SET.mask() != 0 {
+                    // unmask -> mask: only from the vlapic LINT0-ExtINT enabled
+                    if self
+                        .bus
+                        .switch_wire_mode(&[WireMode::Lapic], WireMode::Null)
+                    {
+                        debug!("[VLAPIC] vpic wire mode changed to NULL");
+                    }
+                } else {
+                    // APIC_LVT_M unchanged. No action required.
+                }
+            }
+        }
+
+        let unmasked = self.lvt_last_val(offset) & APIC_LVT_M != 0 && val & APIC_LVT_M == 0;
+        self.store_lvt(offset, val)?;
 
+        // A level-triggered interrupt is pending as long as its pin is asserted, deliver it once unmasked.
+        if unmasked {
+            match offset {
+                ApicRegOffset::LvtLint0 => self.deliver_lint(LintPin::Lint0, false),
+                ApicRegOffset::LvtLint1 => self.deliver_lint(LintPin::Lint1, false),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Store a sanitized LVT value to both the virtual-APIC page and the local copy.
+    fn store_lvt(&mut self, offset: ApicRegOffset, val: u32) -> AxResult {
         match offset {
             ApicRegOffset::LvtTimer => {
-                mask |= LVT_TIMER::TimerMode::SET.mask();
-                val &= mask;
                 self.regs().LVT_TIMER.set(val); // Duplicated, which one should be removed?
                 self.lvt_last.lvt_timer.set(val);
 
                 self.virtual_timer.write_lvt(val)?;
             }
             ApicRegOffset::LvtErr => {
-                val &= mask;
                 self.regs().LVT_ERROR.set(val);
                 self.lvt_last.lvt_err.set(val);
             }
             ApicRegOffset::LvtLint0 => {
-                mask |= LVT_LINT0::TriggerMode::SET.mask();
-                mask |= LVT_LINT0::RemoteIRR::SET.mask();
-                mask |= LVT_LINT0::InterruptInputPinPolarity::SET.mask();
-                mask |= LVT_LINT0::DeliveryMode::SET.mask();
-                val &= mask;
-
-                // vlapic mask/unmask LINT0 for ExtINT?
-                if (val & LVT_LINT0::DeliveryMode::SET.mask())
-                    == LVT_LINT0::DeliveryMode::ExtINT.mask()
-                {
-                    let last = self.lvt_last.lvt_lint0;
-                    if last.is_set(LVT_LINT0::Mask) && val & LVT_LINT0::Mask::SET.mask() == 0 {
-                        // mask -> unmask: may from every vlapic in the vm
-                        warn!("vpic wire mode change to LAPIC, unimplemented");
-                    } else if !last.is_set(LVT_LINT0::Mask)
-                        && val & LVT_LINT0::Mask::SET.mask() != 0
-                    {
-                        // unmask -> mask: only from the vlapic LINT0-ExtINT enabled
-                        warn!("vpic wire mode change to NULL, unimplemented");
-                    } else {
-                        // APIC_LVT_M unchanged. No action required.
-                    }
-                }
-
                 self.regs().LVT_LINT0.set(val);
                 self.lvt_last.lvt_lint0.set(val);
             }
             ApicRegOffset::LvtLint1 => {
-                mask |= LVT_LINT1::TriggerMode::SET.mask();
-                mask |= LVT_LINT1::RemoteIRR::SET.mask();
-                mask |= LVT_LINT1::InterruptInputPinPolarity::SET.mask();
-                mask |= LVT_LINT1::DeliveryMode::SET.mask();
-                val &= mask;
-
                 self.regs().LVT_LINT1.set(val);
                 self.lvt_last.lvt_lint1.set(val);
             }
             ApicRegOffset::LvtCMCI => {
-                mask |= LVT_CMCI::DeliveryMode::SET.mask();
-                val &= mask;
                 self.regs().LVT_CMCI.set(val);
                 self.lvt_last.lvt_cmci.set(val);
             }
             ApicRegOffset::LvtPmc => {
-                mask |= LVT_PERFORMANCE_COUNTER::DeliveryMode::SET.mask();
-                val &= mask;
                 self.regs().LVT_PMI.set(val);
                 self.lvt_last.lvt_perf_count.set(val);
             }
             ApicRegOffset::LvtThermal => {
-                mask |= LVT_THERMAL_MONITOR::DeliveryMode::SET.mask();
-                val &= mask;
                 self.regs().LVT_THERMAL.set(val);
                 self.lvt_last.lvt_thermal.set(val);
             }
@@ -662,6 +1782,10 @@ impl VirtualApicRegs {
         self.regs().LVT_ERROR.modify(LVT_ERROR::Mask::SET);
         self.write_lvt(ApicRegOffset::LvtErr)?;
 
+        for lvt in &self.regs().EXT_LVT {
+            lvt.set(lvt.get() | APIC_LVT_M as u128);
+        }
+
         Ok(())
     }
 
@@ -675,6 +1799,17 @@ impl VirtualApicRegs {
     }
 }
 
+/// Figure 11-12. Interrupt Command Register (ICR)
+/// The fields of the low doubleword written by software: everything but the read-only Delivery Status and Remote
+/// Read Status fields, and the reserved bits.
+const ICR_LO_WRITABLE: u32 = 0x000C_CFFF;
+/// 11.12.9 ICR Operation in x2APIC Mode
+/// Bits 13, 17:16 and 31:20 are reserved in x2APIC mode, and writing them raises #GP. The Delivery Status bit is
+/// removed, writes to it are ignored.
+const ICR_LO_RESERVED_X2APIC: u32 = 0xFFF3_2000;
+/// AMD Extended LVT: the Mask (bit 16), Message Type (bits 10:8) and Vector (bits 7:0) fields.
+const EXT_LVT_WRITABLE: u32 = 0x0001_07FF;
+
 fn extract_index_u32(vector: u32) -> usize {
     vector as usize >> 5
 }
@@ -711,7 +1846,14 @@ impl VirtualApicRegs {
                 warn!("[VLAPIC] read EOI register: {value:#010X}");
             }
             ApicRegOffset::LDR => {
-                value = self.regs().LDR.get() as _;
+                value = self.ldr() as _;
+            }
+            ApicRegOffset::RRR => {
+                if self.is_x2apic_enabled() {
+                    warn!("[VLAPIC] read RRR register: not available in x2APIC mode");
+                    return Err(AxError::InvalidInput);
+                }
+                value = self.regs().RRD.get() as _;
             }
             ApicRegOffset::DFR => {
                 value = self.regs().DFR.get() as _;
@@ -732,30 +1874,29 @@ impl VirtualApicRegs {
                 value = self.regs().ESR.get() as _;
             }
             ApicRegOffset::ICRLow => {
+                self.check_icr_width(width)?;
                 value = self.regs().ICR_LO.get() as _;
-                if self.is_x2apic_enabled() && width == AccessWidth::Qword {
-                    let icr_hi = self.regs().ICR_HI.get() as usize;
-                    value |= icr_hi << 32;
+                if self.is_x2apic_enabled() {
+                    // 11.12.9: the ICR is a single 64-bit MSR in x2APIC mode.
+                    value |= (self.regs().ICR_HI.get() as usize) << 32;
                     debug!("[VLAPIC] read ICR register: {value:#018X}");
-                } else if self.is_x2apic_enabled() ^ (width == AccessWidth::Qword) {
-                    warn!(
-                        "[VLAPIC] Illegal read attempt of ICR register at width {:?} with X2APIC {}",
-                        width,
-                        if self.is_x2apic_enabled() {
-                            "enabled"
-                        } else {
-                            "disabled"
-                        }
-                    );
-                    return Err(AxError::InvalidInput);
                 }
             }
             ApicRegOffset::ICRHi => {
+                if self.is_x2apic_enabled() {
+                    warn!("[VLAPIC] read ICR_HI register: not available in x2APIC mode");
+                    return Err(AxError::InvalidInput);
+                }
                 value = self.regs().ICR_HI.get() as _;
             }
             // Local Vector Table registers.
             ApicRegOffset::LvtCMCI => {
-                value = self.lvt_last.lvt_cmci.get() as _;
+                if self.profile.cmci {
+                    value = self.lvt_last.lvt_cmci.get() as _;
+                } else if self.is_x2apic_enabled() {
+                    warn!("[VLAPIC] read LvtCMCI register: not implemented by the profile");
+                    return Err(AxError::InvalidInput);
+                }
             }
             ApicRegOffset::LvtTimer => {
                 value = self.lvt_last.lvt_timer.get() as _;
@@ -797,6 +1938,31 @@ impl VirtualApicRegs {
             ApicRegOffset::TimerDivConf => {
                 value = self.regs().DCR_TIMER.get() as _;
             }
+            // AMD extended registers.
+            ApicRegOffset::ExtFeature => {
+                if self.amd_ext_reg(offset)?.is_some() {
+                    value = self.regs().EXT_FEATURE.get() as _;
+                }
+            }
+            ApicRegOffset::ExtControl => {
+                if self.amd_ext_reg(offset)?.is_some() {
+                    value = self.regs().EXT_CONTROL.get() as _;
+                }
+            }
+            ApicRegOffset::SEOI => {
+                // Write-only, reads return 0.
+                self.amd_ext_reg(offset)?;
+            }
+            ApicRegOffset::IER(index) => {
+                if self.amd_ext_reg(offset)?.is_some() {
+                    value = self.regs().IER[index.as_usize()].get() as u32 as _;
+                }
+            }
+            ApicRegOffset::ExtLvt(index) => {
+                if self.amd_ext_reg(offset)?.is_some() {
+                    value = self.regs().EXT_LVT[index.as_usize()].get() as u32 as _;
+                }
+            }
             _ => {
                 warn!("[VLAPIC] read unknown APIC register: {offset:?}");
             }
@@ -818,10 +1984,19 @@ impl VirtualApicRegs {
                 // Force APIC ID to be read-only.
                 // self.regs().ID.set(val as _);
             }
+            ApicRegOffset::TPR => {
+                self.regs().TPR.set(data32 & 0xff);
+                self.update_ppr();
+            }
             ApicRegOffset::EOI => {
                 self.process_eoi();
             }
             ApicRegOffset::LDR => {
+                if self.is_x2apic_enabled() {
+                    // 11.12.10.2: the LDR is read-only in x2APIC mode.
+                    warn!("[VLAPIC] write LDR register: read-only in x2APIC mode");
+                    return Err(AxError::InvalidInput);
+                }
                 self.regs().LDR.set(data32);
                 self.write_ldr();
             }
@@ -838,28 +2013,42 @@ impl VirtualApicRegs {
                 self.write_esr();
             }
             ApicRegOffset::ICRLow => {
-                if self.is_x2apic_enabled() && width == AccessWidth::Qword {
+                self.check_icr_width(width)?;
+                if self.is_x2apic_enabled() {
+                    if data32 & ICR_LO_RESERVED_X2APIC != 0 {
+                        warn!("[VLAPIC] write ICR register: reserved bits set in {val:#018X}");
+                        return Err(AxError::InvalidInput);
+                    }
                     debug!("[VLAPIC] write ICR register: {val:#018X} in X2APIC mode");
                     self.regs().ICR_HI.set((val >> 32) as u32);
-                } else if self.is_x2apic_enabled() ^ (width == AccessWidth::Qword) {
-                    warn!(
-                        "[VLAPIC] Illegal read attempt of ICR register at width {:?} with X2APIC {}",
-                        width,
-                        if self.is_x2apic_enabled() {
-                            "enabled"
-                        } else {
-                            "disabled"
-                        }
-                    );
-                    return Err(AxError::InvalidInput);
                 }
-                self.regs().ICR_LO.set(data32);
+                // The read-only status fields are updated by `write_icr`, and reserved bits read as 0.
+                self.regs().ICR_LO.set(data32 & ICR_LO_WRITABLE);
                 self.write_icr()?;
             }
+            ApicRegOffset::ICRHi => {
+                if self.is_x2apic_enabled() {
+                    warn!("[VLAPIC] write ICR_HI register: not available in x2APIC mode");
+                    return Err(AxError::InvalidInput);
+                }
+                // Only the destination field is writable, and the IPI is only sent by the write to ICR_LO.
+                self.regs()
+                    .ICR_HI
+                    .set(data32 & INTERRUPT_COMMAND_HIGH::Destination::SET.mask());
+            }
             // Local Vector Table registers.
             ApicRegOffset::LvtCMCI => {
-                self.regs().LVT_CMCI.set(data32);
-                self.write_lvt(offset)?;
+                if self.profile.cmci {
+                    self.regs().LVT_CMCI.set(data32);
+                    self.write_lvt(offset)?;
+                } else if self.is_x2apic_enabled() {
+                    warn!("[VLAPIC] write LvtCMCI register: not implemented by the profile");
+                    return Err(AxError::InvalidInput);
+                } else {
+                    warn!(
+                        "[VLAPIC] write LvtCMCI register: not implemented by the profile, ignored"
+                    );
+                }
             }
             ApicRegOffset::LvtTimer => {
                 self.regs().LVT_TIMER.set(data32);
@@ -904,12 +2093,47 @@ impl VirtualApicRegs {
             ApicRegOffset::SelfIPI => {
                 if self.is_x2apic_enabled() {
                     self.regs().SELF_IPI.set(data32);
-                    self.handle_self_ipi();
+                    self.handle_self_ipi(data32 & APIC_LVT_VECTOR);
                 } else {
                     warn!("[VLAPIC] write SelfIPI register: unsupported in xAPIC mode");
                     return Err(AxError::InvalidInput);
                 }
             }
+            // AMD extended registers.
+            ApicRegOffset::ExtControl => {
+                if self.amd_ext_reg(offset)?.is_some() {
+                    self.regs()
+                        .EXT_CONTROL
+                        .set(data32 & self.ext_control_writable());
+                }
+            }
+            ApicRegOffset::SEOI => {
+                if self.amd_ext_reg(offset)?.is_some()
+                    && self
+                        .regs()
+                        .EXT_CONTROL
+                        .is_set(EXTENDED_APIC_CONTROL::SeoiEn)
+                {
+                    self.regs().SEOI.set(data32);
+                    self.process_seoi(data32 & APIC_LVT_VECTOR);
+                }
+            }
+            ApicRegOffset::IER(index) => {
+                if self.amd_ext_reg(offset)?.is_some() {
+                    self.regs().IER[index.as_usize()].set(data32 as _);
+                }
+            }
+            ApicRegOffset::ExtLvt(index) => {
+                if self.amd_ext_reg(offset)?.is_some() {
+                    // Only the mask, message type and vector are writable, and the mask can't be cleared while
+                    // the local APIC is software-disabled, as for the other LVT registers.
+                    let mut val = data32 & EXT_LVT_WRITABLE;
+                    if !self.is_software_enabled() {
+                        val |= APIC_LVT_M;
+                    }
+                    self.regs().EXT_LVT[index.as_usize()].set(val as _);
+                }
+            }
             _ => {
                 warn!("[VLAPIC] write unsupported APIC register: {offset:?}");
                 return Err(AxError::InvalidInput);
@@ -921,3 +2145,627 @@ impl VirtualApicRegs {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec::Vec};
+
+    use axaddrspace::device::AccessWidth;
+    use axerrno::AxError;
+    use tock_registers::interfaces::{Readable, Writeable};
+
+    use super::{LintPin, Ordering, VirtualApicRegs};
+    use crate::bus::ApicBus;
+    use crate::consts::{ApicRegOffset, ExtLvtIndex, IERIndex, IRRIndex, ISRIndex, TMRIndex};
+    use crate::regs::APIC_BASE;
+    use crate::state::LapicState;
+
+    fn new_regs(vcpu_num: usize, vcpu_id: usize) -> VirtualApicRegs {
+        VirtualApicRegs::new(Arc::new(ApicBus::new(1, vcpu_num).unwrap()), vcpu_id).unwrap()
+    }
+
+    fn read(regs: &VirtualApicRegs, offset: ApicRegOffset) -> usize {
+        regs.handle_read(offset, AccessWidth::Dword).unwrap()
+    }
+
+    fn write(regs: &mut VirtualApicRegs, offset: ApicRegOffset, val: usize) {
+        regs.handle_write(offset, val, AccessWidth::Dword).unwrap();
+    }
+
+    /// Software-enable the local APIC, with the reset spurious vector.
+    fn enable(regs: &mut VirtualApicRegs) {
+        write(regs, ApicRegOffset::SIVR, 0x1FF);
+    }
+
+    /// Switch the local APIC to x2APIC mode.
+    fn enable_x2apic(regs: &mut VirtualApicRegs) {
+        regs.apic_base
+            .modify(APIC_BASE::XAPIC_ENABLED::SET + APIC_BASE::X2APIC_Enabled::SET);
+        regs.regs().ID.set(regs.vapic_id);
+        regs.publish_dest();
+    }
+
+    #[test]
+    fn test_vcpu_out_of_bus() {
+        let bus = Arc::new(ApicBus::new(1, 2).unwrap());
+        assert!(VirtualApicRegs::new(bus.clone(), 1).is_ok());
+        assert_eq!(
+            VirtualApicRegs::new(bus, 2).err(),
+            Some(AxError::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn test_lvt_reserved_delivery_mode() {
+        let mut regs = new_regs(1, 0);
+        enable(&mut regs);
+
+        // SMI is valid in the thermal entry, INIT is not and keeps the previous mode.
+        write(&mut regs, ApicRegOffset::LvtThermal, 0x240);
+        assert_eq!(read(&regs, ApicRegOffset::LvtThermal), 0x240);
+        write(&mut regs, ApicRegOffset::LvtThermal, 0x541);
+        assert_eq!(read(&regs, ApicRegOffset::LvtThermal), 0x241);
+
+        // Delivery mode 011B is reserved in the LINT entries.
+        write(&mut regs, ApicRegOffset::LvtLint0, 0x700);
+        assert_eq!(read(&regs, ApicRegOffset::LvtLint0), 0x700);
+        write(&mut regs, ApicRegOffset::LvtLint0, 0x320);
+        assert_eq!(read(&regs, ApicRegOffset::LvtLint0), 0x720);
+
+        // Timer mode 11B is reserved.
+        write(&mut regs, ApicRegOffset::LvtTimer, 0x2_0030);
+        assert_eq!(read(&regs, ApicRegOffset::LvtTimer), 0x2_0030);
+        write(&mut regs, ApicRegOffset::LvtTimer, 0x6_0031);
+        assert_eq!(read(&regs, ApicRegOffset::LvtTimer), 0x2_0031);
+
+        // In x2APIC mode the write fails, and the register keeps its value.
+        enable_x2apic(&mut regs);
+        assert!(
+            regs.handle_write(ApicRegOffset::LvtThermal, 0x542, AccessWidth::Dword)
+                .is_err()
+        );
+        assert_eq!(read(&regs, ApicRegOffset::LvtThermal), 0x241);
+        assert_eq!(regs.regs().LVT_THERMAL.get(), 0x241);
+    }
+
+    #[test]
+    fn test_lvt_masked_while_software_disabled() {
+        let mut regs = new_regs(1, 0);
+
+        // The mask can't be cleared while software-disabled.
+        write(&mut regs, ApicRegOffset::LvtLint1, 0x400);
+        assert_eq!(read(&regs, ApicRegOffset::LvtLint1), 0x1_0400);
+        write(&mut regs, ApicRegOffset::LvtTimer, 0x30);
+        assert_eq!(read(&regs, ApicRegOffset::LvtTimer), 0x1_0030);
+
+        enable(&mut regs);
+        write(&mut regs, ApicRegOffset::LvtLint1, 0x400);
+        assert_eq!(read(&regs, ApicRegOffset::LvtLint1), 0x400);
+        write(&mut regs, ApicRegOffset::LvtErr, 0xFE);
+        assert_eq!(read(&regs, ApicRegOffset::LvtErr), 0xFE);
+
+        // Software-disabling the local APIC masks every LVT entry.
+        write(&mut regs, ApicRegOffset::SIVR, 0xFF);
+        assert_eq!(read(&regs, ApicRegOffset::LvtLint1), 0x1_0400);
+        assert_eq!(read(&regs, ApicRegOffset::LvtErr), 0x1_00FE);
+        assert_eq!(read(&regs, ApicRegOffset::LvtTimer), 0x1_0030);
+    }
+
+    #[test]
+    fn test_spurious_vector_when_masked_by_ppr() {
+        let mut regs = new_regs(1, 0);
+        write(&mut regs, ApicRegOffset::SIVR, 0x1EF);
+
+        assert!(regs.accept_intr(0x41, false));
+        assert_eq!(regs.pending_intr(), Some(0x41));
+
+        // The guest raises the TPR before the interrupt is acknowledged.
+        write(&mut regs, ApicRegOffset::TPR, 0x50);
+        assert_eq!(regs.acknowledge_intr(), Some(0xEF));
+        assert_eq!(read(&regs, ApicRegOffset::IRR(IRRIndex::IRRIndex2)), 0x2);
+        assert_eq!(read(&regs, ApicRegOffset::ISR(ISRIndex::ISRIndex2)), 0);
+
+        write(&mut regs, ApicRegOffset::TPR, 0);
+        assert_eq!(regs.acknowledge_intr(), Some(0x41));
+        assert_eq!(read(&regs, ApicRegOffset::IRR(IRRIndex::IRRIndex2)), 0);
+        assert_eq!(read(&regs, ApicRegOffset::ISR(ISRIndex::ISRIndex2)), 0x2);
+        assert_eq!(regs.acknowledge_intr(), None);
+    }
+
+    #[test]
+    fn test_level_lint0_unmasked_while_asserted() {
+        let mut regs = new_regs(1, 0);
+        enable(&mut regs);
+
+        // Fixed, level-triggered and masked LINT0 with the pin asserted.
+        write(&mut regs, ApicRegOffset::LvtLint0, 0x1_8030);
+        regs.set_lint(LintPin::Lint0, true);
+        assert_eq!(regs.pending_intr(), None);
+
+        // Unmasking delivers the interrupt and sets Remote IRR.
+        write(&mut regs, ApicRegOffset::LvtLint0, 0x8030);
+        assert_eq!(regs.pending_intr(), Some(0x30));
+        assert_eq!(read(&regs, ApicRegOffset::LvtLint0), 0xC030);
+        assert_eq!(
+            read(&regs, ApicRegOffset::TMR(TMRIndex::TMRIndex1)),
+            0x1_0000
+        );
+
+        // It is not delivered again before the EOI, and again after it while the pin stays asserted.
+        assert_eq!(regs.acknowledge_intr(), Some(0x30));
+        write(&mut regs, ApicRegOffset::LvtLint0, 0x1_8030);
+        write(&mut regs, ApicRegOffset::LvtLint0, 0x8030);
+        assert_eq!(regs.pending_intr(), None);
+        write(&mut regs, ApicRegOffset::EOI, 0);
+        assert_eq!(read(&regs, ApicRegOffset::LvtLint0), 0xC030);
+        assert_eq!(regs.pending_intr(), Some(0x30));
+    }
+
+    #[test]
+    fn test_guest_interrupt_status_and_eoi_exit_bitmap() {
+        let mut regs = new_regs(1, 0);
+        enable(&mut regs);
+        assert_eq!(regs.guest_interrupt_status(), 0);
+        assert_eq!(regs.eoi_exit_bitmap(), [0; 4]);
+
+        // RVI is the highest vector in the IRR, SVI the highest in the ISR.
+        assert!(regs.accept_intr(0x31, false));
+        assert_eq!(regs.acknowledge_intr(), Some(0x31));
+        assert!(regs.accept_intr(0x52, true));
+        assert!(regs.accept_intr(0x40, false));
+        assert_eq!(regs.guest_interrupt_status(), 0x3152);
+
+        // Level-triggered vectors: from the TMR, a level LINT0, and those routed by the VMM.
+        write(&mut regs, ApicRegOffset::LvtLint0, 0x8071);
+        regs.set_level_triggered_vector(0xC3, true);
+        assert_eq!(
+            regs.eoi_exit_bitmap(),
+            [0, 1 << 0x12 | 1 << 0x31, 0, 1 << 0x03]
+        );
+        regs.set_level_triggered_vector(0xC3, false);
+        assert_eq!(regs.eoi_exit_bitmap(), [0, 1 << 0x12 | 1 << 0x31, 0, 0]);
+
+        // A virtual EOI goes through the EOI path.
+        assert_eq!(regs.acknowledge_intr(), Some(0x52));
+        regs.handle_virtual_eoi(0x52);
+        assert_eq!(regs.guest_interrupt_status(), 0x3140);
+    }
+
+    #[test]
+    fn test_tpr_threshold() {
+        let mut regs = new_regs(1, 0);
+        enable(&mut regs);
+        assert_eq!(regs.tpr_threshold(), 0);
+
+        // The highest pending interrupt is deliverable, no threshold.
+        assert!(regs.accept_intr(0x45, false));
+        write(&mut regs, ApicRegOffset::TPR, 0x30);
+        assert_eq!(regs.tpr_threshold(), 0);
+
+        // It is masked by the TPR, the threshold is its priority class.
+        write(&mut regs, ApicRegOffset::TPR, 0x40);
+        assert_eq!(regs.tpr_threshold(), 4);
+        assert_eq!(regs.pending_intr(), None);
+
+        // The guest lowers VTPR on the virtual-APIC page, with garbage in the reserved bits.
+        regs.regs().TPR.set(0x1_0030);
+        assert_eq!(regs.handle_tpr_below_threshold(), Some(0x45));
+        assert_eq!(read(&regs, ApicRegOffset::TPR), 0x30);
+        assert_eq!(read(&regs, ApicRegOffset::PPR), 0x30);
+        assert_eq!(regs.tpr_threshold(), 0);
+    }
+
+    #[test]
+    fn test_level_ipi_eoi_broadcast() {
+        use crate::test_utils::{
+            ioapic_eoi, kick_vcpu, take_injected, take_ioapic_eois, take_kicked,
+        };
+
+        let mut bus = ApicBus::new(1, 2).unwrap();
+        bus.set_vcpu_kicker(kick_vcpu);
+        bus.set_ioapic_eoi_handler(ioapic_eoi);
+        let bus = Arc::new(bus);
+        let mut sender = VirtualApicRegs::new(bus.clone(), 0).unwrap();
+        let mut target = VirtualApicRegs::new(bus, 1).unwrap();
+        enable(&mut sender);
+        enable(&mut target);
+
+        // Fixed, level-triggered and asserted IPI to all excluding self.
+        write(&mut sender, ApicRegOffset::ICRLow, 0xC_C061);
+        assert_eq!(take_kicked(), [(1, 1)]);
+        assert_eq!(take_injected(), []);
+
+        assert!(target.sync_irr());
+        assert_eq!(read(&target, ApicRegOffset::TMR(TMRIndex::TMRIndex3)), 0x2);
+        assert_eq!(target.acknowledge_intr(), Some(0x61));
+        write(&mut target, ApicRegOffset::EOI, 0);
+        assert_eq!(take_ioapic_eois(), [(1, 0x61)]);
+
+        // Edge-triggered IPIs clear the TMR bit, and their EOI is not broadcast.
+        write(&mut sender, ApicRegOffset::ICRLow, 0xC_4061);
+        assert!(target.sync_irr());
+        assert_eq!(read(&target, ApicRegOffset::TMR(TMRIndex::TMRIndex3)), 0);
+        assert_eq!(target.acknowledge_intr(), Some(0x61));
+        write(&mut target, ApicRegOffset::EOI, 0);
+        assert_eq!(take_ioapic_eois(), []);
+    }
+
+    #[test]
+    fn test_icr_x2apic_destination_mode() {
+        let bus = Arc::new(ApicBus::new(1, 20).unwrap());
+        let mut apics: Vec<_> = (0..20)
+            .map(|id| {
+                let mut regs = VirtualApicRegs::new(bus.clone(), id).unwrap();
+                enable(&mut regs);
+                enable_x2apic(&mut regs);
+                regs
+            })
+            .collect();
+        // The local APICs which received an interrupt.
+        fn pending(apics: &mut [VirtualApicRegs]) -> Vec<usize> {
+            apics
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(id, regs)| regs.sync_irr().then_some(id))
+                .collect()
+        }
+
+        // Logical: cluster 1, logical IDs 0 and 3.
+        let icr = 0x0001_0009_0000_4850;
+        apics[0]
+            .handle_write(ApicRegOffset::ICRLow, icr, AccessWidth::Qword)
+            .unwrap();
+        assert_eq!(pending(&mut apics), [16, 19]);
+
+        // Physical: APIC ID 9, which is in cluster 0 for logical destinations.
+        let icr = 0x0000_0009_0000_4050;
+        apics[0]
+            .handle_write(ApicRegOffset::ICRLow, icr, AccessWidth::Qword)
+            .unwrap();
+        assert_eq!(pending(&mut apics), [9]);
+    }
+
+    #[test]
+    fn test_restore_apic_id() {
+        let mut regs = new_regs(2, 1);
+        write(&mut regs, ApicRegOffset::TPR, 0x20);
+        let state = regs.save();
+
+        // The state of vCPU 1 is rejected by vCPU 0, which keeps its registers.
+        let mut other = new_regs(2, 0);
+        assert_eq!(other.restore(&state).err(), Some(AxError::InvalidInput));
+        assert_eq!(read(&other, ApicRegOffset::TPR), 0);
+        assert_eq!(read(&other, ApicRegOffset::ID), 0);
+
+        // The ID register is in the format of the saved mode.
+        enable_x2apic(&mut regs);
+        let mut state = regs.save();
+        assert!(regs.restore(&state).is_ok());
+        state.regs[0x2] = 1 << 24;
+        assert_eq!(regs.restore(&state).err(), Some(AxError::InvalidInput));
+        assert_eq!(read(&regs, ApicRegOffset::ID), 1);
+    }
+
+    #[test]
+    fn test_restore_pv_eoi_pending() {
+        use axaddrspace::{GuestPhysAddr, HostVirtAddr};
+        use axvisor_api::vmm::VMId;
+        use core::sync::atomic::AtomicU8;
+
+        static GUEST_FLAG: AtomicU8 = AtomicU8::new(0);
+        fn translate(_vm_id: VMId, gpa: GuestPhysAddr) -> Option<HostVirtAddr> {
+            (gpa.as_usize() == 0x1000)
+                .then(|| HostVirtAddr::from_usize(GUEST_FLAG.as_ptr() as usize))
+        }
+
+        let mut bus = ApicBus::new(1, 1).unwrap();
+        bus.set_guest_phys_translator(translate);
+        let mut regs = VirtualApicRegs::new(Arc::new(bus), 0).unwrap();
+        enable(&mut regs);
+        regs.write_pv_eoi_msr(0x1001).unwrap();
+        assert!(regs.accept_intr(0x40, false));
+        assert_eq!(regs.acknowledge_intr(), Some(0x40));
+        let state = regs.save();
+
+        // The guest may skip the EOI of vector 40H.
+        regs.sync_pv_eoi_to_guest();
+        assert_eq!(GUEST_FLAG.load(Ordering::SeqCst), 1);
+
+        // An invalid MSR is rejected before anything changes: reserved bit, or flag not in guest memory.
+        let isr2 = ApicRegOffset::ISR(ISRIndex::ISRIndex2);
+        for msr in [0x1003, 0x2001] {
+            write(&mut regs, ApicRegOffset::TPR, 0x20);
+            let bad = LapicState {
+                pv_eoi_msr: msr,
+                ..state
+            };
+            assert_eq!(regs.restore(&bad).err(), Some(AxError::InvalidInput));
+            assert_eq!(read(&regs, ApicRegOffset::TPR), 0x20);
+            assert_eq!(GUEST_FLAG.load(Ordering::SeqCst), 1);
+        }
+
+        // The pending EOI is dropped with the replaced ISR, and the flag cleared.
+        regs.restore(&state).unwrap();
+        assert_eq!(GUEST_FLAG.load(Ordering::SeqCst), 0);
+        assert_eq!(read(&regs, ApicRegOffset::TPR), 0);
+        assert_eq!(read(&regs, isr2), 1);
+        assert_eq!(regs.pv_eoi_msr(), 0x1001);
+        regs.sync_pv_eoi_from_guest();
+        assert_eq!(read(&regs, isr2), 1);
+
+        // Paravirtual EOI works on with the restored MSR.
+        regs.sync_pv_eoi_to_guest();
+        GUEST_FLAG.store(0, Ordering::SeqCst);
+        regs.sync_pv_eoi_from_guest();
+        assert_eq!(read(&regs, isr2), 0);
+    }
+
+    #[test]
+    fn test_reset_values() {
+        let mut regs = new_regs(2, 1);
+        let check_reset = |regs: &VirtualApicRegs| {
+            assert_eq!(read(regs, ApicRegOffset::DFR), 0xFFFF_FFFF);
+            assert_eq!(read(regs, ApicRegOffset::SIVR), 0xFF);
+            assert_eq!(read(regs, ApicRegOffset::TPR), 0);
+            for lvt in [
+                ApicRegOffset::LvtTimer,
+                ApicRegOffset::LvtThermal,
+                ApicRegOffset::LvtPmc,
+                ApicRegOffset::LvtLint0,
+                ApicRegOffset::LvtLint1,
+                ApicRegOffset::LvtErr,
+            ] {
+                assert_eq!(read(regs, lvt), 0x1_0000, "{lvt}");
+            }
+            assert_eq!(regs.pending_intr(), None);
+        };
+        check_reset(&regs);
+        assert_eq!(read(&regs, ApicRegOffset::ID), 1 << 24);
+        assert_eq!(regs.apic_base(), 0xFEE0_0800);
+
+        let dirty = |regs: &mut VirtualApicRegs| {
+            enable(regs);
+            write(regs, ApicRegOffset::DFR, 0x0FFF_FFFF);
+            write(regs, ApicRegOffset::TPR, 0x20);
+            write(regs, ApicRegOffset::LvtLint0, 0x700);
+            write(regs, ApicRegOffset::LvtTimer, 0x30);
+            assert!(regs.accept_intr(0x40, false));
+        };
+
+        // INIT keeps the APIC ID and IA32_APIC_BASE, x2APIC mode included.
+        dirty(&mut regs);
+        enable_x2apic(&mut regs);
+        regs.reset_init().unwrap();
+        check_reset(&regs);
+        assert_eq!(read(&regs, ApicRegOffset::ID), 1);
+        assert_eq!(regs.apic_base(), 0xFEE0_0C00);
+
+        // Power-on resets them.
+        dirty(&mut regs);
+        regs.reset_power_on().unwrap();
+        check_reset(&regs);
+        assert_eq!(read(&regs, ApicRegOffset::ID), 1 << 24);
+        assert_eq!(regs.apic_base(), 0xFEE0_0800);
+    }
+
+    #[test]
+    fn test_init_ipi() {
+        use crate::test_utils::{kick_vcpu, take_kicked};
+
+        let mut bus = ApicBus::new(1, 2).unwrap();
+        bus.set_vcpu_kicker(kick_vcpu);
+        let bus = Arc::new(bus);
+        let mut sender = VirtualApicRegs::new(bus.clone(), 0).unwrap();
+        let mut target = VirtualApicRegs::new(bus, 1).unwrap();
+        for regs in [&mut sender, &mut target] {
+            enable(regs);
+            enable_x2apic(regs);
+        }
+        assert!(target.accept_intr(0x40, false));
+        let send_ipi = |sender: &mut VirtualApicRegs, icr_low: usize| {
+            sender
+                .handle_write(ApicRegOffset::ICRLow, 1 << 32 | icr_low, AccessWidth::Qword)
+                .unwrap();
+        };
+
+        // INIT level de-assert is ignored.
+        send_ipi(&mut sender, 0x8500);
+        assert!(!target.sync_irr());
+        assert!(!target.take_init());
+        assert_eq!(take_kicked(), []);
+
+        // INIT resets the target, discarding its pending interrupts.
+        send_ipi(&mut sender, 0x4500);
+        assert_eq!(take_kicked(), [(1, 1)]);
+        target.sync_irr();
+        assert!(target.take_init());
+        assert!(!target.take_init());
+        assert!(!target.is_software_enabled());
+        assert_eq!(target.pending_intr(), None);
+
+        // Start-Up IPIs are dropped.
+        send_ipi(&mut sender, 0x4608);
+        assert!(!target.sync_irr());
+        assert!(!target.take_init());
+    }
+    #[test]
+    fn test_remote_read_after_drop() {
+        use crate::profile::LapicProfile;
+
+        let bus = Arc::new(ApicBus::new(1, 2).unwrap());
+        let mut target = VirtualApicRegs::new(bus.clone(), 0).unwrap();
+        let mut sender = VirtualApicRegs::new(bus, 1).unwrap();
+        sender.set_profile(LapicProfile {
+            remote_read: true,
+            ..Default::default()
+        });
+        write(&mut target, ApicRegOffset::TPR, 0x20);
+
+        // Remote read of the TPR of APIC ID 0, in physical destination mode.
+        write(&mut sender, ApicRegOffset::ICRLow, 0x308);
+        assert_eq!(read(&sender, ApicRegOffset::RRR), 0x20);
+        assert_eq!(read(&sender, ApicRegOffset::ICRLow), 0x2_0308);
+
+        // The page of a dropped local APIC is no longer read.
+        drop(target);
+        write(&mut sender, ApicRegOffset::ICRLow, 0x308);
+        assert_eq!(read(&sender, ApicRegOffset::ICRLow), 0x308);
+    }
+
+    #[test]
+    fn test_icr_read_back() {
+        let bus = Arc::new(ApicBus::new(1, 2).unwrap());
+        let mut regs = VirtualApicRegs::new(bus.clone(), 0).unwrap();
+        let mut other = VirtualApicRegs::new(bus, 1).unwrap();
+        enable(&mut regs);
+        enable(&mut other);
+
+        // Writes to ICR_HI only keep the destination field, and send nothing.
+        write(&mut regs, ApicRegOffset::ICRHi, 0x01FF_FFFF);
+        assert_eq!(read(&regs, ApicRegOffset::ICRHi), 0x0100_0000);
+        assert!(!other.sync_irr());
+
+        // Reserved bits read as 0, and the status fields as idle and invalid after the IPI is sent.
+        write(&mut regs, ApicRegOffset::ICRLow, 0xFFF3_3030);
+        assert_eq!(read(&regs, ApicRegOffset::ICRLow), 0x30);
+        assert!(other.sync_irr());
+        assert_eq!(other.pending_intr(), Some(0x30));
+
+        // In x2APIC mode the ICR is a single 64-bit register.
+        enable_x2apic(&mut regs);
+        let icr = 0x0000_0001_0000_4031;
+        regs.handle_write(ApicRegOffset::ICRLow, icr, AccessWidth::Qword)
+            .unwrap();
+        assert_eq!(
+            regs.handle_read(ApicRegOffset::ICRLow, AccessWidth::Qword),
+            Ok(icr)
+        );
+        assert!(
+            regs.handle_read(ApicRegOffset::ICRLow, AccessWidth::Dword)
+                .is_err()
+        );
+        assert!(
+            regs.handle_read(ApicRegOffset::ICRHi, AccessWidth::Dword)
+                .is_err()
+        );
+        // Bits 13, 17:16 and 31:20 are reserved.
+        for reserved in [1 << 13, 1 << 16, 1 << 20] {
+            assert!(
+                regs.handle_write(ApicRegOffset::ICRLow, icr | reserved, AccessWidth::Qword)
+                    .is_err()
+            );
+        }
+        assert_eq!(
+            regs.handle_read(ApicRegOffset::ICRLow, AccessWidth::Qword),
+            Ok(icr)
+        );
+    }
+
+    #[test]
+    fn test_avic_incomplete_ipi() {
+        use crate::test_utils::{kick_vcpu, take_kicked};
+
+        fn doorbell(_host_apic_id: u32) {}
+
+        let mut bus = ApicBus::new(1, 2).unwrap();
+        bus.enable_avic(doorbell);
+        bus.set_vcpu_kicker(kick_vcpu);
+        let bus = Arc::new(bus);
+        let mut sender = VirtualApicRegs::new(bus.clone(), 0).unwrap();
+        let mut target = VirtualApicRegs::new(bus, 1).unwrap();
+        for regs in [&mut sender, &mut target] {
+            enable(regs);
+            regs.enable_avic().unwrap();
+        }
+
+        // The processor set vector 41H in the IRR of APIC ID 1, which is not running.
+        target.irr(2).fetch_or(1 << 1, Ordering::SeqCst);
+        let icr = (1 << 24) << 32 | 0x4041;
+        sender.handle_avic_incomplete_ipi(icr, 1 << 32).unwrap();
+        assert_eq!(take_kicked(), [(1, 1)]);
+        assert!(target.sync_irr());
+        assert_eq!(target.pending_intr(), Some(0x41));
+
+        // SIPIs are dropped.
+        let icr = (1 << 24) << 32 | 0x4608;
+        sender.handle_avic_incomplete_ipi(icr, 0).unwrap();
+        assert!(!target.sync_irr());
+        assert!(!target.take_init());
+    }
+
+    #[test]
+    fn test_amd_ext_registers() {
+        use crate::profile::{AmdExtApic, LapicProfile};
+
+        // Without the extended register space, accesses are ignored in xAPIC mode and fail in x2APIC mode.
+        let mut regs = new_regs(1, 0);
+        write(&mut regs, ApicRegOffset::ExtControl, 0x3);
+        assert_eq!(read(&regs, ApicRegOffset::ExtControl), 0);
+        assert_eq!(read(&regs, ApicRegOffset::ExtFeature), 0);
+        enable_x2apic(&mut regs);
+        assert!(
+            regs.handle_read(ApicRegOffset::ExtFeature, AccessWidth::Dword)
+                .is_err()
+        );
+        assert!(
+            regs.handle_write(ApicRegOffset::ExtControl, 0x3, AccessWidth::Dword)
+                .is_err()
+        );
+
+        // IER and two extended LVT registers, without SEOI.
+        let mut regs = new_regs(1, 0);
+        regs.set_profile(LapicProfile::amd(AmdExtApic {
+            ier: true,
+            seoi: false,
+            ext_lvt_count: 2,
+        }));
+        assert_eq!(
+            read(&regs, ApicRegOffset::Version) & 0x8000_0000,
+            0x8000_0000
+        );
+        assert_eq!(read(&regs, ApicRegOffset::ExtFeature), 0x2_0001);
+        write(&mut regs, ApicRegOffset::ExtControl, 0x7);
+        assert_eq!(read(&regs, ApicRegOffset::ExtControl), 0x1);
+
+        // Extended LVT registers stay masked while software-disabled, and only exist up to the count.
+        let lvt1 = ApicRegOffset::ExtLvt(ExtLvtIndex::ExtLvtIndex1);
+        let lvt2 = ApicRegOffset::ExtLvt(ExtLvtIndex::ExtLvtIndex2);
+        write(&mut regs, lvt1, 0xFFFF);
+        assert_eq!(read(&regs, lvt1), 0x1_07FF);
+        enable(&mut regs);
+        write(&mut regs, lvt1, 0x430);
+        assert_eq!(read(&regs, lvt1), 0x430);
+        write(&mut regs, lvt2, 0x430);
+        assert_eq!(read(&regs, lvt2), 0);
+
+        // Interrupts of vectors disabled in the IER are held in the IRR.
+        let ier2 = ApicRegOffset::IER(IERIndex::IERIndex2);
+        assert!(regs.accept_intr(0x41, false));
+        assert_eq!(regs.pending_intr(), None);
+        write(&mut regs, ier2, 0x2);
+        assert_eq!(read(&regs, ier2), 0x2);
+        assert_eq!(regs.pending_intr(), Some(0x41));
+    }
+
+    #[test]
+    fn test_amd_seoi() {
+        use crate::profile::{AmdExtApic, LapicProfile};
+
+        let mut regs = new_regs(1, 0);
+        regs.set_profile(LapicProfile::amd(AmdExtApic::default()));
+        enable(&mut regs);
+        let isr2 = ApicRegOffset::ISR(ISRIndex::ISRIndex2);
+        for vector in [0x40, 0x50] {
+            assert!(regs.accept_intr(vector, false));
+            assert_eq!(regs.acknowledge_intr(), Some(vector as u8));
+        }
+
+        // SEOI is ignored until enabled in the Extended APIC Control register.
+        write(&mut regs, ApicRegOffset::SEOI, 0x40);
+        assert_eq!(read(&regs, isr2), 0x1_0001);
+        write(&mut regs, ApicRegOffset::ExtControl, 0x2);
+        write(&mut regs, ApicRegOffset::SEOI, 0x40);
+        assert_eq!(read(&regs, isr2), 0x1_0000);
+        assert_eq!(read(&regs, ApicRegOffset::PPR), 0x50);
+    }
+}